@@ -26,7 +26,9 @@ if ($clean) {
 }
 
 if ($test) {
-	cargo test
+	# Tests run on the build machine, not the default target
+	$HOST=(rustc -vV | Select-String "host: (.*)").Matches[0].Groups[1].Value
+	cargo test --target $HOST
 	if (-Not $?) {
 		exit
 	}
@@ -0,0 +1,273 @@
+use volatile::Volatile;
+use bit_field::BitField;
+
+use core::mem;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+
+use super::ClockGate;
+
+#[repr(C)]
+struct DmaRegs {
+    cr: Volatile<u32>,
+    es: Volatile<u32>,
+    _pad0: u32,
+    erq: Volatile<u32>,
+    _pad1: u32,
+    eei: Volatile<u32>,
+    ceei: Volatile<u8>,
+    seei: Volatile<u8>,
+    cerq: Volatile<u8>,
+    serq: Volatile<u8>,
+    cdne: Volatile<u8>,
+    ssrt: Volatile<u8>,
+    cerr: Volatile<u8>,
+    cint: Volatile<u8>,
+    _pad2: u32,
+    int: Volatile<u32>,
+    _pad3: u32,
+    err: Volatile<u32>,
+    _pad4: u32,
+    hrs: Volatile<u32>,
+    _pad5: [u32; 50],
+    dchpri: [Volatile<u8>; 16],
+    _pad6: [u32; 956],
+    tcd: [Tcd; 16]
+}
+
+#[repr(C)]
+struct Tcd {
+    saddr: Volatile<u32>,
+    soff: Volatile<i16>,
+    attr: Volatile<u16>,
+    nbytes: Volatile<u32>,
+    slast: Volatile<i32>,
+    daddr: Volatile<u32>,
+    doff: Volatile<i16>,
+    citer: Volatile<u16>,
+    dlastsga: Volatile<i32>,
+    csr: Volatile<u16>,
+    biter: Volatile<u16>
+}
+
+#[repr(C)]
+struct DmaMuxRegs {
+    chcfg: [Volatile<u8>; 16]
+}
+
+pub struct Dma {
+    reg: UnsafeCell<&'static mut DmaRegs>,
+    mux: UnsafeCell<&'static mut DmaMuxRegs>,
+    locks: [AtomicBool; 16],
+    _gates: (ClockGate, ClockGate)
+}
+
+pub struct DmaChannel<'a> {
+    dma: &'a Dma,
+    channel: usize
+}
+
+// A transfer into memory that's still running. It owns the buffer
+// the DMA is writing to, and gives it back once stopped.
+pub struct ReadTransfer<'c, 'a: 'c> {
+    channel: &'c mut DmaChannel<'a>,
+    buf: &'static mut [u8],
+    circular: bool
+}
+
+// Completion callbacks, stored as function pointers so the interrupt
+// handler can find them without a reference to the channel.
+static CALLBACKS: [AtomicUsize; 16] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+impl Dma {
+    pub unsafe fn new(mux_gate: ClockGate, dma_gate: ClockGate) -> Dma {
+        let reg = &mut *(0x40008000 as *mut DmaRegs);
+        let mux = &mut *(0x40021000 as *mut DmaMuxRegs);
+        Dma {
+            reg: UnsafeCell::new(reg),
+            mux: UnsafeCell::new(mux),
+            locks: Default::default(),
+            _gates: (mux_gate, dma_gate)
+        }
+    }
+
+    pub fn channel(&self, c: usize) -> DmaChannel<'_> {
+        assert!(c < 16);
+        let was_init = self.locks[c].swap(true, Ordering::Relaxed);
+        if was_init {
+            panic!("DMA channel {} is already in use", c);
+        }
+        DmaChannel { dma: self, channel: c }
+    }
+
+    fn reg(&self) -> &'static mut DmaRegs {
+        // NOTE: Channels only touch their own TCD and mux slot, and
+        // the shared registers they use (SERQ, CERQ, CINT...) are
+        // write-only bit-setters, so they can't trample each other.
+        unsafe {
+            *self.reg.get()
+        }
+    }
+
+    fn mux(&self) -> &'static mut DmaMuxRegs {
+        unsafe {
+            *self.mux.get()
+        }
+    }
+}
+
+impl <'a> DmaChannel<'a> {
+    pub fn channel(&self) -> usize {
+        self.channel
+    }
+
+    // Route a DMA request source (see the DMA MUX chapter of the
+    // reference manual) to this channel.
+    pub fn set_source(&mut self, source: u8) {
+        assert!(source < 64);
+        let chcfg = &mut self.dma.mux().chcfg[self.channel];
+        chcfg.write(0);
+        if source != 0 {
+            chcfg.write(source | 0x80);
+        }
+    }
+
+    // Move `len` bytes from memory at `src` to a single peripheral
+    // byte register at `dst`, one byte per request.
+    pub unsafe fn memory_to_peripheral(&mut self, src: *const u8, dst: *mut u8, len: usize) {
+        self.configure(src as u32, 1, dst as u32, 0, len, false);
+    }
+
+    // Move `len` bytes from a single peripheral byte register at
+    // `src` into memory at `dst`. In circular mode the destination
+    // wraps back to the start and the transfer never finishes.
+    pub unsafe fn peripheral_to_memory(&mut self, src: *const u8, dst: *mut u8, len: usize, circular: bool) {
+        self.configure(src as u32, 0, dst as u32, 1, len, circular);
+    }
+
+    // Run `callback` from the DMA interrupt when the major loop
+    // completes (and, in circular mode, when it's half done).
+    pub fn on_complete(&mut self, callback: Option<fn()>) {
+        let ptr = callback.map(|f| f as usize).unwrap_or(0);
+        CALLBACKS[self.channel].store(ptr, Ordering::Relaxed);
+        let circular = !self.tcd().csr.read().get_bit(3);
+        self.tcd().csr.update(|csr| {
+            csr.set_bit(1, callback.is_some());
+            csr.set_bit(2, callback.is_some() && circular);
+        });
+    }
+
+    pub fn start(&mut self) {
+        self.dma.reg().serq.write(self.channel as u8);
+    }
+
+    pub fn stop(&mut self) {
+        self.dma.reg().cerq.write(self.channel as u8);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.tcd().csr.read().get_bit(7)
+    }
+
+    // How many bytes of the current major loop are left to move.
+    pub fn remaining(&self) -> usize {
+        self.tcd().citer.read().get_bits(0..15) as usize
+    }
+
+    unsafe fn configure(&mut self, src: u32, soff: i16, dst: u32, doff: i16, len: usize, circular: bool) {
+        if len == 0 || len > 0x7FFF {
+            panic!("Invalid DMA transfer length: {}", len);
+        }
+        self.stop();
+        self.dma.reg().cdne.write(self.channel as u8);
+
+        let tcd = self.tcd();
+        tcd.csr.write(0);
+        tcd.saddr.write(src);
+        tcd.soff.write(soff);
+        tcd.attr.write(0); // 8-bit source and destination
+        tcd.nbytes.write(1);
+        tcd.slast.write(if circular { -(soff as i32 * len as i32) } else { 0 });
+        tcd.daddr.write(dst);
+        tcd.doff.write(doff);
+        tcd.citer.write(len as u16);
+        tcd.biter.write(len as u16);
+        tcd.dlastsga.write(if circular { -(doff as i32 * len as i32) } else { 0 });
+        tcd.csr.update(|csr| {
+            // One-shot transfers turn their request off when they
+            // finish, circular ones keep going.
+            csr.set_bit(3, !circular);
+        });
+    }
+
+    fn tcd(&self) -> &'static mut Tcd {
+        &mut self.dma.reg().tcd[self.channel]
+    }
+}
+
+impl <'c, 'a: 'c> ReadTransfer<'c, 'a> {
+    // The channel has to be set up to write into `buf`, for example
+    // with `peripheral_to_memory`.
+    pub unsafe fn new(channel: &'c mut DmaChannel<'a>, buf: &'static mut [u8], circular: bool) -> ReadTransfer<'c, 'a> {
+        ReadTransfer { channel, buf, circular }
+    }
+
+    // How far into the buffer the DMA has written. In circular mode
+    // this goes back to zero at the start of each pass.
+    pub fn position(&self) -> usize {
+        // CITER is reloaded when the major loop finishes, so a
+        // finished one-shot transfer looks like it never started.
+        if !self.circular && self.channel.is_complete() {
+            self.buf.len()
+        } else {
+            self.buf.len() - self.channel.remaining()
+        }
+    }
+
+    // The bytes written so far. In circular mode these are only the
+    // ones from the current pass, and the DMA overwrites them on the
+    // next, so copy them out before then.
+    pub fn received(&self) -> &[u8] {
+        &self.buf[..self.position()]
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.circular && self.channel.is_complete()
+    }
+
+    pub fn stop(self) -> &'static mut [u8] {
+        self.channel.stop();
+        self.buf
+    }
+}
+
+impl <'a> Drop for DmaChannel<'a> {
+    fn drop(&mut self) {
+        self.stop();
+        self.set_source(0);
+        self.on_complete(None);
+        self.dma.locks[self.channel].store(false, Ordering::Relaxed);
+    }
+}
+
+// Every DMA channel vector points here. We work out which channels
+// finished from the INT register instead of having a handler each.
+pub extern "C" fn dma_isr() {
+    let reg = unsafe { &mut *(0x40008000 as *mut DmaRegs) };
+    let int = reg.int.read();
+    for channel in 0..16 {
+        if int.get_bit(channel) {
+            reg.cint.write(channel);
+            let callback = CALLBACKS[channel as usize].load(Ordering::Relaxed);
+            if callback != 0 {
+                let callback: fn() = unsafe { mem::transmute(callback) };
+                callback();
+            }
+        }
+    }
+}
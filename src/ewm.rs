@@ -0,0 +1,97 @@
+use volatile::Volatile;
+use bit_field::BitField;
+
+use core::mem::ManuallyDrop;
+
+use super::{ClockGate,EwmOut,Interrupt,Nvic,without_interrupts};
+
+#[repr(C)]
+struct EwmRegs {
+    ctrl: Volatile<u8>,
+    serv: Volatile<u8>,
+    cmpl: Volatile<u8>,
+    cmph: Volatile<u8>
+}
+
+// The EWM counter runs from the 1kHz low power oscillator and is only
+// 8 bits wide, so every time here is in whole milliseconds up to 254.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct EwmConfig {
+    // EWM_OUT is asserted if the EWM isn't refreshed within this long
+    // of the last refresh.
+    pub timeout_ms: u32,
+    // Refreshing sooner than this after the last refresh also asserts
+    // EWM_OUT.
+    pub window_ms: Option<u32>
+}
+
+// The External Watchdog Monitor. Unlike the watchdog it doesn't reset
+// the chip; it drives EWM_OUT low so that external hardware can be
+// reset (or made safe) when the firmware stops refreshing it.
+//
+// Once started, the EWM keeps counting until the next reset, so
+// dropping the Ewm only gives up the ability to refresh it. The clock
+// gate and the EWM_OUT pin are deliberately never released: EWM_OUT
+// still asserts once the timeout runs out.
+pub struct Ewm<'a> {
+    reg: &'static mut EwmRegs,
+    _out: ManuallyDrop<EwmOut<'a>>,
+    _gate: ManuallyDrop<ClockGate>
+}
+
+impl <'a> Ewm<'a> {
+    // The EWM's configuration can only be written once after each
+    // reset, so it's configured and started here and can't be changed
+    // afterwards.
+    pub unsafe fn new(out: EwmOut<'a>, config: EwmConfig, gate: ClockGate) -> Ewm<'a> {
+        if config.timeout_ms == 0 || config.timeout_ms > 254 {
+            panic!("EWM timeout out of range: {}ms", config.timeout_ms);
+        }
+        let window = match config.window_ms {
+            Some(ms) if ms >= config.timeout_ms => panic!("EWM window must be shorter than the timeout"),
+            Some(ms) => ms as u8,
+            None => 0
+        };
+
+        let reg = &mut *(0x40061000 as *mut EwmRegs);
+        if reg.ctrl.read().get_bit(0) {
+            panic!("EWM is already running");
+        }
+        reg.cmpl.write(window);
+        reg.cmph.write(config.timeout_ms as u8);
+        reg.ctrl.write(1);
+
+        Ewm {reg, _out: ManuallyDrop::new(out), _gate: ManuallyDrop::new(gate)}
+    }
+
+    pub fn feed(&mut self) {
+        // Both halves of the refresh have to land within 15 bus
+        // clocks of each other.
+        without_interrupts(|| {
+            self.reg.serv.write(0xB4);
+            self.reg.serv.write(0x2C);
+        });
+    }
+
+    // Raise IRQ 22 when EWM_OUT asserts. The vector is shared with the
+    // watchdog, so the handler is registered with
+    // `Interrupt::Watchdog.set_handler`. The interrupt stays asserted
+    // until the handler calls `Ewm::clear_interrupt`.
+    pub fn enable_interrupt(&mut self, nvic: &mut Nvic) {
+        self.reg.ctrl.update(|ctrl| {
+            ctrl.set_bit(3, true);
+        });
+        nvic.enable(Interrupt::Watchdog as usize);
+    }
+
+    // Clearing INTEN is the only way to deassert the EWM interrupt, so
+    // this has to be callable from the handler, which has no `Ewm`.
+    pub fn clear_interrupt() {
+        unsafe {
+            let reg = &mut *(0x40061000 as *mut EwmRegs);
+            reg.ctrl.update(|ctrl| {
+                ctrl.set_bit(3, false);
+            });
+        }
+    }
+}
@@ -0,0 +1,268 @@
+#[cfg(not(test))]
+use core::arch::asm;
+use core::ptr;
+
+use super::without_interrupts;
+
+// The Cortex-M4 core exceptions, numbered by their position in the
+// vector table. Entry 0 is the initial stack pointer and entry 1 is
+// reset, neither of which can be handled.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Exception {
+    Nmi = 2,
+    HardFault = 3,
+    MemManage = 4,
+    BusFault = 5,
+    UsageFault = 6,
+    SvCall = 11,
+    DebugMonitor = 12,
+    PendSv = 14,
+    SysTick = 15
+}
+
+// The MK20DX256 peripheral interrupts, numbered by IRQ (which is also
+// what `Nvic` takes). Their vector table entries start at 16.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Interrupt {
+    Dma0 = 0,
+    Dma1 = 1,
+    Dma2 = 2,
+    Dma3 = 3,
+    Dma4 = 4,
+    Dma5 = 5,
+    Dma6 = 6,
+    Dma7 = 7,
+    Dma8 = 8,
+    Dma9 = 9,
+    Dma10 = 10,
+    Dma11 = 11,
+    Dma12 = 12,
+    Dma13 = 13,
+    Dma14 = 14,
+    Dma15 = 15,
+    DmaError = 16,
+    Ftfl = 18,
+    ReadCollision = 19,
+    Lvd = 20,
+    Llw = 21,
+    // Shared by the watchdog and the EWM. The watchdog driver's
+    // handler sits in the vector table and passes anything that isn't
+    // a watchdog timeout on to the handler registered here.
+    Watchdog = 22,
+    I2c0 = 24,
+    I2c1 = 25,
+    Spi0 = 26,
+    Spi1 = 27,
+    Can0Message = 29,
+    Can0BusOff = 30,
+    Can0Error = 31,
+    Can0TxWarning = 32,
+    Can0RxWarning = 33,
+    Can0WakeUp = 34,
+    I2s0Tx = 35,
+    I2s0Rx = 36,
+    Uart0Lon = 44,
+    Uart0Status = 45,
+    Uart0Error = 46,
+    Uart1Status = 47,
+    Uart1Error = 48,
+    Uart2Status = 49,
+    Uart2Error = 50,
+    Adc0 = 57,
+    Adc1 = 58,
+    Cmp0 = 59,
+    Cmp1 = 60,
+    Cmp2 = 61,
+    Ftm0 = 62,
+    Ftm1 = 63,
+    Ftm2 = 64,
+    Cmt = 65,
+    RtcAlarm = 66,
+    RtcSeconds = 67,
+    Pit0 = 68,
+    Pit1 = 69,
+    Pit2 = 70,
+    Pit3 = 71,
+    Pdb0 = 72,
+    Usb0 = 73,
+    UsbDcd = 74,
+    Dac0 = 81,
+    Tsi0 = 83,
+    Mcg = 84,
+    LpTimer = 85,
+    PortA = 87,
+    PortB = 88,
+    PortC = 89,
+    PortD = 90,
+    PortE = 91,
+    Software = 94
+}
+
+pub const VECTOR_COUNT: usize = 16 + 95;
+
+// Handlers registered at runtime, indexed by vector number. Every
+// vector that isn't wired straight to a driver goes through
+// `dispatch`, which looks here.
+//
+// These stand in for weak default handlers. Weak symbols can only be
+// overridden from another object file, and the handlers that would
+// override them live in this same crate, where a second definition of
+// the symbol is an error rather than an override.
+static mut HANDLERS: [Option<fn()>; VECTOR_COUNT] = [None; VECTOR_COUNT];
+
+impl Exception {
+    pub fn set_handler(self, handler: Option<fn()>) {
+        set_handler(self as usize, handler);
+    }
+
+    fn from_vector(vector: usize) -> Option<Exception> {
+        match vector {
+            2 => Some(Exception::Nmi),
+            3 => Some(Exception::HardFault),
+            4 => Some(Exception::MemManage),
+            5 => Some(Exception::BusFault),
+            6 => Some(Exception::UsageFault),
+            11 => Some(Exception::SvCall),
+            12 => Some(Exception::DebugMonitor),
+            14 => Some(Exception::PendSv),
+            15 => Some(Exception::SysTick),
+            _ => None
+        }
+    }
+}
+
+impl Interrupt {
+    // Handlers set here replace the default, which panics. The
+    // interrupt still has to be enabled in the `Nvic`.
+    pub fn set_handler(self, handler: Option<fn()>) {
+        if let Some(driver) = self.driver() {
+            panic!("{:?} is handled by the {} driver", self, driver);
+        }
+        set_handler(16 + self as usize, handler);
+    }
+
+    // Run whatever is registered for this interrupt, for drivers that
+    // share their vector with it.
+    pub unsafe fn run_handler(self) {
+        run_handler(16 + self as usize);
+    }
+
+    // The driver whose handler sits directly in the vector table for
+    // this interrupt, if any.
+    fn driver(self) -> Option<&'static str> {
+        match self as usize {
+            0..=15 => Some("DMA"),
+            45 | 47 | 49 => Some("UART"),
+            _ => None
+        }
+    }
+
+    fn from_irq(irq: usize) -> Option<Interrupt> {
+        match irq {
+            0 => Some(Interrupt::Dma0),
+            1 => Some(Interrupt::Dma1),
+            2 => Some(Interrupt::Dma2),
+            3 => Some(Interrupt::Dma3),
+            4 => Some(Interrupt::Dma4),
+            5 => Some(Interrupt::Dma5),
+            6 => Some(Interrupt::Dma6),
+            7 => Some(Interrupt::Dma7),
+            8 => Some(Interrupt::Dma8),
+            9 => Some(Interrupt::Dma9),
+            10 => Some(Interrupt::Dma10),
+            11 => Some(Interrupt::Dma11),
+            12 => Some(Interrupt::Dma12),
+            13 => Some(Interrupt::Dma13),
+            14 => Some(Interrupt::Dma14),
+            15 => Some(Interrupt::Dma15),
+            16 => Some(Interrupt::DmaError),
+            18 => Some(Interrupt::Ftfl),
+            19 => Some(Interrupt::ReadCollision),
+            20 => Some(Interrupt::Lvd),
+            21 => Some(Interrupt::Llw),
+            22 => Some(Interrupt::Watchdog),
+            24 => Some(Interrupt::I2c0),
+            25 => Some(Interrupt::I2c1),
+            26 => Some(Interrupt::Spi0),
+            27 => Some(Interrupt::Spi1),
+            29 => Some(Interrupt::Can0Message),
+            30 => Some(Interrupt::Can0BusOff),
+            31 => Some(Interrupt::Can0Error),
+            32 => Some(Interrupt::Can0TxWarning),
+            33 => Some(Interrupt::Can0RxWarning),
+            34 => Some(Interrupt::Can0WakeUp),
+            35 => Some(Interrupt::I2s0Tx),
+            36 => Some(Interrupt::I2s0Rx),
+            44 => Some(Interrupt::Uart0Lon),
+            45 => Some(Interrupt::Uart0Status),
+            46 => Some(Interrupt::Uart0Error),
+            47 => Some(Interrupt::Uart1Status),
+            48 => Some(Interrupt::Uart1Error),
+            49 => Some(Interrupt::Uart2Status),
+            50 => Some(Interrupt::Uart2Error),
+            57 => Some(Interrupt::Adc0),
+            58 => Some(Interrupt::Adc1),
+            59 => Some(Interrupt::Cmp0),
+            60 => Some(Interrupt::Cmp1),
+            61 => Some(Interrupt::Cmp2),
+            62 => Some(Interrupt::Ftm0),
+            63 => Some(Interrupt::Ftm1),
+            64 => Some(Interrupt::Ftm2),
+            65 => Some(Interrupt::Cmt),
+            66 => Some(Interrupt::RtcAlarm),
+            67 => Some(Interrupt::RtcSeconds),
+            68 => Some(Interrupt::Pit0),
+            69 => Some(Interrupt::Pit1),
+            70 => Some(Interrupt::Pit2),
+            71 => Some(Interrupt::Pit3),
+            72 => Some(Interrupt::Pdb0),
+            73 => Some(Interrupt::Usb0),
+            74 => Some(Interrupt::UsbDcd),
+            81 => Some(Interrupt::Dac0),
+            83 => Some(Interrupt::Tsi0),
+            84 => Some(Interrupt::Mcg),
+            85 => Some(Interrupt::LpTimer),
+            87 => Some(Interrupt::PortA),
+            88 => Some(Interrupt::PortB),
+            89 => Some(Interrupt::PortC),
+            90 => Some(Interrupt::PortD),
+            91 => Some(Interrupt::PortE),
+            94 => Some(Interrupt::Software),
+            _ => None
+        }
+    }
+}
+
+fn set_handler(vector: usize, handler: Option<fn()>) {
+    without_interrupts(|| {
+        unsafe {
+            ptr::write_volatile(&mut HANDLERS[vector], handler);
+        }
+    });
+}
+
+// The handler for every vector without a driver of its own. Which
+// vector we're handling is in the IPSR.
+#[cfg(not(test))]
+pub unsafe extern "C" fn dispatch() {
+    let ipsr: u32;
+    asm!("mrs {}, IPSR", out(reg) ipsr, options(nomem, nostack));
+    run_handler(ipsr as usize & 0x1FF);
+}
+
+unsafe fn run_handler(vector: usize) {
+    match ptr::read_volatile(&HANDLERS[vector]) {
+        Some(handler) => handler(),
+        None => default_handler(vector)
+    }
+}
+
+fn default_handler(vector: usize) -> ! {
+    if let Some(exception) = Exception::from_vector(vector) {
+        panic!("Unhandled exception {:?}", exception);
+    }
+    match Interrupt::from_irq(vector.wrapping_sub(16)) {
+        Some(irq) => panic!("Unhandled interrupt {:?}", irq),
+        None => panic!("Unhandled exception {}", vector)
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg_attr(not(test), no_std)]
+#![no_builtins]
+
+#![deny(warnings)]
+#![allow(unknown_lints)]
+#![allow(clippy::unreadable_literal)]
+#![allow(clippy::empty_loop)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::needless_pass_by_value)]
+
+// Under no_std `core` is already in scope here, but host tests get
+// `std` instead.
+#[cfg(test)]
+extern crate core;
+extern crate volatile;
+extern crate bit_field;
+extern crate embedded_hal;
+extern crate embedded_hal_nb;
+extern crate nb;
+
+pub mod dma;
+pub mod ewm;
+pub mod interrupt;
+pub mod mcg;
+#[cfg(test)]
+mod mock;
+pub mod nvic;
+pub mod osc;
+pub mod peripherals;
+pub mod port;
+pub mod ringbuf;
+pub mod sim;
+pub mod systick;
+pub mod uart;
+pub mod watchdog;
+
+use dma::*;
+use ewm::*;
+use interrupt::*;
+use mcg::*;
+use nvic::*;
+use osc::*;
+use port::*;
+use ringbuf::*;
+use sim::*;
+use systick::*;
+use uart::*;
+use watchdog::*;
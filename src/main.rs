@@ -1,31 +1,23 @@
-#![feature(asm,panic_info_message)]
 #![no_std]
 #![no_main]
 #![no_builtins]
 
 #![deny(warnings)]
 #![allow(unknown_lints)]
-#![allow(clippy::unreadable_literal)]
 #![allow(clippy::empty_loop)]
-#![allow(clippy::needless_pass_by_value)]
 
 extern crate volatile;
-extern crate bit_field;
-
-mod mcg;
-mod osc;
-mod port;
-mod sim;
-mod uart;
-mod watchdog;
-
-use mcg::*;
-use osc::*;
-use port::*;
-use sim::*;
-use uart::*;
-use watchdog::*;
+extern crate teensy;
 
+use teensy::dma::*;
+use teensy::interrupt::*;
+use teensy::mcg::*;
+use teensy::peripherals::*;
+use teensy::port::*;
+use teensy::uart::*;
+use teensy::watchdog::*;
+
+use core::ptr;
 use core::slice;
 use core::fmt::Write;
 use volatile::Volatile;
@@ -33,25 +25,29 @@ use volatile::Volatile;
 static mut PORT: Option<Port> = None;
 static mut WRITER: Option<Uart<'static, 'static>> = None;
 
-extern fn main() {
+extern "C" fn main() {
+    // The watchdog has to be unlocked soon after reset, and setting up
+    // .data and .bss takes longer the more statics there are, so it's
+    // disabled before anything else. The peripherals can only be taken
+    // once .bss is zeroed, so this goes through a handle of its own.
     unsafe {
         Watchdog::new().disable();
+        setup_data();
         setup_bss();
     }
+    let Peripherals { watchdog, mut osc, mcg, mut sim, .. } = Peripherals::take().unwrap();
 
     // Enable the crystal oscillator with 10pf of capacitance
-    let osc_token = Osc::new().enable(10);
+    let osc_token = osc.enable(10);
 
     // Set our clocks:
     // core: 72Mhz
     // peripheral: 36MHz
     // flash: 24MHz
-    let mut sim = Sim::new();
     sim.set_dividers(1, 2, 3);
     // We would also set the USB divider here if we wanted to use it.
 
     // Now we can start setting up the MCG for our needs.
-    let mcg = Mcg::new();
     if let Clock::Fei(mut fei) = mcg.clock() {
         // Our 16MHz xtal is "very fast", and needs to be divided
         // by 512 to be in the acceptable FLL range.
@@ -69,11 +65,19 @@ extern fn main() {
     // we are modifying a global variable.
     unsafe {
         PORT = Some(sim.port(PortName::B));
-        let rx = PORT.as_ref().unwrap().pin(16).make_rx();
-        let tx = PORT.as_ref().unwrap().pin(17).make_tx();
-        WRITER = Some(sim.uart(0, Some(rx), Some(tx), (468, 24)));
+        let port = (*ptr::addr_of!(PORT)).as_ref().unwrap();
+        let rx = port.pin(16).make_rx();
+        let tx = port.pin(17).make_tx();
+        WRITER = Some(sim.uart(0, Some(rx), Some(tx), 9600).unwrap());
     };
 
+    // If the watchdog reset us, report what it caught.
+    if let Some(crash) = CrashRecord::take() {
+        let writer = unsafe { (*ptr::addr_of_mut!(WRITER)).as_mut().unwrap() };
+        writeln!(writer, "{}", crash).unwrap();
+        writeln!(writer, "Watchdog reset count: {}", watchdog.reset_count()).unwrap();
+    }
+
     let portc = sim.port(PortName::C);
     let mut gpio = portc.pin(5).make_gpio();
     gpio.output();
@@ -82,15 +86,30 @@ extern fn main() {
     loop {};
 }
 
-extern {
+extern "C" {
     fn _stack_top();
     static mut _bss_start: u8;
     static mut _bss_end: u8;
+    static mut _data_start: u8;
+    static mut _data_end: u8;
+    static _data_load: u8;
+}
+
+// Copies initialized statics (and `.ramfunc` code) from flash into RAM.
+unsafe fn setup_data() {
+    let data_start = ptr::addr_of_mut!(_data_start);
+    let data_end = ptr::addr_of_mut!(_data_end);
+    let data_len = data_end as usize - data_start as usize;
+    let data = slice::from_raw_parts_mut(data_start, data_len);
+    let load = slice::from_raw_parts(&_data_load as *const u8, data_len);
+    for (d, l) in data.iter_mut().zip(load) {
+        *d = *l;
+    }
 }
 
 unsafe fn setup_bss() {
-    let bss_start = &mut _bss_start as *mut u8;
-    let bss_end = &mut _bss_end as *mut u8;
+    let bss_start = ptr::addr_of_mut!(_bss_start);
+    let bss_end = ptr::addr_of_mut!(_bss_end);
     let bss_len = bss_end as usize - bss_start as usize;
     let bss = slice::from_raw_parts_mut(bss_start, bss_len);
     for b in &mut bss.iter_mut() {
@@ -98,12 +117,38 @@ unsafe fn setup_bss() {
     }
 }
 
+// The first 16 entries are the Cortex-M core exceptions, followed by
+// all 95 K20 peripheral IRQs. Drivers with their own handlers are
+// wired in directly; everything else goes through `dispatch`, which
+// calls whatever was registered with `set_handler` or panics.
 #[link_section = ".vectors"]
 #[no_mangle]
-pub static _VECTORS: [unsafe extern fn(); 2] = [
-    _stack_top,
-    main,
-];
+pub static _VECTORS: [unsafe extern "C" fn(); VECTOR_COUNT] = {
+    let mut vectors = [dispatch as unsafe extern "C" fn(); VECTOR_COUNT];
+    vectors[0] = _stack_top;
+    vectors[1] = main;
+    vectors[16] = dma_isr;
+    vectors[16 + 1] = dma_isr;
+    vectors[16 + 2] = dma_isr;
+    vectors[16 + 3] = dma_isr;
+    vectors[16 + 4] = dma_isr;
+    vectors[16 + 5] = dma_isr;
+    vectors[16 + 6] = dma_isr;
+    vectors[16 + 7] = dma_isr;
+    vectors[16 + 8] = dma_isr;
+    vectors[16 + 9] = dma_isr;
+    vectors[16 + 10] = dma_isr;
+    vectors[16 + 11] = dma_isr;
+    vectors[16 + 12] = dma_isr;
+    vectors[16 + 13] = dma_isr;
+    vectors[16 + 14] = dma_isr;
+    vectors[16 + 15] = dma_isr;
+    vectors[16 + 22] = watchdog_isr;
+    vectors[16 + 45] = uart0_status;
+    vectors[16 + 47] = uart1_status;
+    vectors[16 + 49] = uart2_status;
+    vectors
+};
 
 const FSEC: u8 = 0xDE;
 const FOPT: u8 = 0xF9;
@@ -117,11 +162,14 @@ pub static _FLASHCONFIG: [u8; 16] = [
 
 #[panic_handler]
 fn teensy_panic(pi: &core::panic::PanicInfo) -> ! {
-    if let Some(uart) = unsafe { WRITER.as_mut() } {
-        write!(uart, "Panic occured! ");
-        if let Some(format_args) = pi.message() {
-            core::fmt::write(uart, *format_args).unwrap();
-        }
+    if let Some(uart) = unsafe { (*ptr::addr_of_mut!(WRITER)).as_mut() } {
+        // We may have panicked inside an interrupt handler, so don't
+        // count on the UART IRQ to send the message for us.
+        uart.disable_interrupts();
+        let _ = write!(uart, "Panic occured! {}", pi.message());
+        // Writes return as soon as the data is queued, so make sure
+        // it's actually out before we reset.
+        uart.flush();
     }
 
     // Reset the MCU after we've printed our panic.
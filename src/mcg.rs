@@ -4,9 +4,7 @@ use bit_field::BitField;
 
 use super::OscToken;
 
-use core::sync::atomic::{AtomicBool,ATOMIC_BOOL_INIT,Ordering};
-
-#[repr(C,packed)]
+#[repr(C)]
 struct McgRegs {
     c1: Volatile<u8>,
     c2: Volatile<u8>,
@@ -28,6 +26,12 @@ pub struct Mcg {
     reg: &'static mut McgRegs
 }
 
+// The Teensy 3.x crystal
+const XTAL_HZ: u32 = 16_000_000;
+const RTC_HZ: u32 = 32_768;
+const SLOW_IRC_HZ: u32 = 32_768;
+const FAST_IRC_HZ: u32 = 4_000_000;
+
 pub struct Fei {
     mcg: Mcg
 }
@@ -46,15 +50,9 @@ pub enum Clock {
     Pbe(Pbe)
 }
 
-static MCG_INIT: AtomicBool = ATOMIC_BOOL_INIT;
-
 impl Mcg {
-    pub fn new() -> Mcg {
-        let was_init = MCG_INIT.swap(true, Ordering::Relaxed);
-        if was_init {
-            panic!("Cannot initialize MCG: It's already active");
-        }
-        let reg = unsafe { &mut *(0x40064000 as *mut McgRegs) };
+    pub unsafe fn new() -> Mcg {
+        let reg = &mut *(0x40064000 as *mut McgRegs);
         Mcg {reg}
     }
 
@@ -72,11 +70,67 @@ impl Mcg {
             _ => panic!("The current clock mode cannot be represented as a known struct")
         }
     }
+
+    // MCGOUTCLK in Hz, worked out from the mode the MCG is in right
+    // now. This only reads the MCG, so it doesn't need to own it.
+    pub fn output_hz() -> u32 {
+        let reg = unsafe { &*(0x40064000 as *const McgRegs) };
+        output_hz(reg)
+    }
+}
+
+fn output_hz(reg: &McgRegs) -> u32 {
+    match reg.s.read().get_bits(2..4) {
+        0 => {
+            let reference = if reg.s.read().get_bit(4) {
+                SLOW_IRC_HZ
+            } else {
+                external_hz(reg) / fll_divider(reg)
+            };
+            let c4 = reg.c4.read();
+            let factor = match (c4.get_bit(7), c4.get_bits(5..7)) {
+                (false, range) => 640 * (u32::from(range) + 1),
+                (true, 0) => 732,
+                (true, 1) => 1464,
+                (true, 2) => 2197,
+                (true, _) => 2929
+            };
+            reference * factor
+        },
+        1 => if reg.c2.read().get_bit(0) {
+            FAST_IRC_HZ >> reg.sc.read().get_bits(1..4)
+        } else {
+            SLOW_IRC_HZ
+        },
+        2 => external_hz(reg),
+        _ => {
+            let prdiv = u64::from(reg.c5.read().get_bits(0..5)) + 1;
+            let vdiv = u64::from(reg.c6.read().get_bits(0..5)) + 24;
+            (u64::from(external_hz(reg)) * vdiv / prdiv) as u32
+        }
+    }
 }
 
-impl Drop for Mcg {
-    fn drop(&mut self) {
-        MCG_INIT.store(false, Ordering::Relaxed);
+fn external_hz(reg: &McgRegs) -> u32 {
+    if reg.c7.read().get_bit(0) {
+        RTC_HZ
+    } else {
+        XTAL_HZ
+    }
+}
+
+// The divider between the external reference and the FLL, which
+// depends on the crystal's frequency range.
+fn fll_divider(reg: &McgRegs) -> u32 {
+    let frdiv = reg.c1.read().get_bits(3..6);
+    if reg.c2.read().get_bits(4..6) == OscRange::Low as u8 {
+        1 << frdiv
+    } else {
+        match frdiv {
+            6 => 1280,
+            7 => 1536,
+            _ => 32 << frdiv
+        }
     }
 }
 
@@ -152,11 +206,11 @@ impl Fei {
 
 impl Fbe {
     pub fn enable_pll(self, numerator: u8, denominator: u8) -> Pbe {
-        if numerator < 24 || numerator > 55 {
+        if !(24..=55).contains(&numerator) {
             panic!("Invalid PLL VCO divide factor: {}", numerator);
         }
 
-        if denominator < 1 || denominator > 25 {
+        if !(1..=25).contains(&denominator) {
             panic!("Invalid PLL reference divide factor: {}", denominator);
         }
 
@@ -193,3 +247,33 @@ impl Pbe {
         while self.mcg.reg.s.read().get_bits(2..4) != 3 {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock;
+
+    #[test]
+    fn fei_after_reset() {
+        let reg = mock::regs::<McgRegs>();
+        reg.c1.write(0x04);
+        reg.s.write(0x10);
+        assert_eq!(output_hz(reg), 20_971_520);
+    }
+
+    #[test]
+    fn fbe_runs_from_the_crystal() {
+        let reg = mock::regs::<McgRegs>();
+        reg.s.write(2 << 2);
+        assert_eq!(output_hz(reg), 16_000_000);
+    }
+
+    #[test]
+    fn pee_at_72mhz() {
+        let reg = mock::regs::<McgRegs>();
+        reg.s.write(3 << 2);
+        reg.c5.write(6 - 1);
+        reg.c6.write(0x40 | (27 - 24));
+        assert_eq!(output_hz(reg), 72_000_000);
+    }
+}
@@ -0,0 +1,9 @@
+use std::mem;
+
+// A zeroed stand-in for a register block, so drivers can be tested on
+// the host. It's leaked, since drivers hold their registers forever.
+pub fn regs<T>() -> &'static mut T {
+    unsafe {
+        Box::leak(Box::new(mem::zeroed()))
+    }
+}
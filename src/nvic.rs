@@ -0,0 +1,74 @@
+use volatile::Volatile;
+
+#[cfg(not(test))]
+use core::arch::asm;
+
+#[repr(C)]
+struct NvicRegs {
+    iser: [Volatile<u32>; 8],
+    _pad0: [u32; 24],
+    icer: [Volatile<u32>; 8],
+    _pad1: [u32; 24],
+    ispr: [Volatile<u32>; 8],
+    _pad2: [u32; 24],
+    icpr: [Volatile<u32>; 8],
+    _pad3: [u32; 24],
+    iabr: [Volatile<u32>; 8],
+    _pad4: [u32; 56],
+    ipr: [Volatile<u8>; 240]
+}
+
+pub struct Nvic {
+    reg: &'static mut NvicRegs
+}
+
+impl Nvic {
+    pub unsafe fn new() -> Nvic {
+        let reg = &mut *(0xE000E100 as *mut NvicRegs);
+        Nvic {reg}
+    }
+
+    pub fn enable(&mut self, irq: usize) {
+        assert!(irq < 240);
+        self.reg.iser[irq / 32].write(1 << (irq % 32));
+    }
+
+    pub fn disable(&mut self, irq: usize) {
+        assert!(irq < 240);
+        self.reg.icer[irq / 32].write(1 << (irq % 32));
+    }
+
+    pub fn clear_pending(&mut self, irq: usize) {
+        assert!(irq < 240);
+        self.reg.icpr[irq / 32].write(1 << (irq % 32));
+    }
+
+    // The K20 only implements the top four bits of each priority.
+    pub fn set_priority(&mut self, irq: usize, priority: u8) {
+        assert!(irq < 240);
+        self.reg.ipr[irq].write(priority & 0xF0);
+    }
+}
+
+// Run `f` with interrupts masked, restoring the previous mask after.
+#[cfg(not(test))]
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let primask: u32;
+    unsafe {
+        asm!("mrs {}, PRIMASK", out(reg) primask);
+        asm!("cpsid i");
+    }
+    let result = f();
+    if primask & 1 == 0 {
+        unsafe {
+            asm!("cpsie i");
+        }
+    }
+    result
+}
+
+// Host tests have no interrupts to mask.
+#[cfg(test)]
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    f()
+}
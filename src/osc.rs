@@ -1,9 +1,7 @@
 use volatile::Volatile;
 use bit_field::BitField;
 
-use core::sync::atomic::{AtomicBool,ATOMIC_BOOL_INIT,Ordering};
-
-#[repr(C,packed)]
+#[repr(C)]
 struct OscRegs {
     cr: Volatile<u8>
 }
@@ -16,15 +14,9 @@ pub struct OscToken {
     _private: ()
 }
 
-static OSC_INIT: AtomicBool = ATOMIC_BOOL_INIT;
-
 impl Osc {
-    pub fn new() -> Osc {
-        let was_init = OSC_INIT.swap(true, Ordering::Relaxed);
-        if was_init {
-            panic!("Cannot initialize OSC: It's already active");
-        }
-        let reg = unsafe { &mut *(0x40065000 as *mut OscRegs) };
+    pub unsafe fn new() -> Osc {
+        let reg = &mut *(0x40065000 as *mut OscRegs);
         Osc {reg}
     }
 
@@ -50,12 +42,6 @@ impl Osc {
     }
 }
 
-impl Drop for Osc {
-    fn drop(&mut self) {
-        OSC_INIT.store(false, Ordering::Relaxed);
-    }
-}
-
 impl OscToken {
     fn new() -> OscToken {
         OscToken { _private: () }
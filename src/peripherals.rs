@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicBool,Ordering};
+
+use super::{Mcg,Nvic,Osc,Sim,SysTick,Watchdog};
+
+pub struct Peripherals {
+    pub watchdog: Watchdog,
+    pub osc: Osc,
+    pub mcg: Mcg,
+    pub nvic: Nvic,
+    pub sim: Sim,
+    pub systick: SysTick
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl Peripherals {
+    pub fn take() -> Option<Peripherals> {
+        let was_taken = TAKEN.swap(true, Ordering::Relaxed);
+        if was_taken {
+            None
+        } else {
+            unsafe {
+                Some(Peripherals::steal())
+            }
+        }
+    }
+
+    // Creates every peripheral without checking whether they've
+    // already been handed out. The caller has to make sure it
+    // doesn't fight with the real owners.
+    pub unsafe fn steal() -> Peripherals {
+        Peripherals {
+            watchdog: Watchdog::new(),
+            osc: Osc::new(),
+            mcg: Mcg::new(),
+            nvic: Nvic::new(),
+            sim: Sim::new(),
+            systick: SysTick::new()
+        }
+    }
+}
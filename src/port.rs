@@ -1,18 +1,37 @@
 use volatile::Volatile;
 use bit_field::BitField;
 
+use core::fmt;
 use core::cell::UnsafeCell;
-use core::sync::atomic::{AtomicBool,Ordering};
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicU8,Ordering};
+
+use embedded_hal::digital::{ErrorType,InputPin,OutputPin,StatefulOutputPin};
 
 use super::ClockGate;
 
 #[derive(Clone,Copy)]
 pub enum PortName {
+    A,
     B,
-    C
+    C,
+    D,
+    E
+}
+
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub enum PinRole {
+    Free,
+    Unassigned,
+    Gpio,
+    UartRx(u8),
+    UartTx(u8),
+    UartRts(u8),
+    UartCts(u8),
+    EwmOut
 }
 
-#[repr(C,packed)]
+#[repr(C)]
 struct PortRegs {
     pcr: [Volatile<u32>; 32],
     gpclr: Volatile<u32>,
@@ -23,7 +42,9 @@ struct PortRegs {
 
 pub struct Port {
     reg: UnsafeCell<&'static mut PortRegs>,
-    locks: [AtomicBool; 32],
+    gpio: *mut GpioBitband,
+    name: PortName,
+    roles: [AtomicU8; 32],
     _gate: ClockGate,
 }
 
@@ -34,15 +55,29 @@ pub struct Pin<'a> {
 
 pub struct Tx<'a> {
     uart: u8,
-    _pin: Pin<'a>
+    pin: Pin<'a>
 }
 
 pub struct Rx<'a> {
     uart: u8,
-    _pin: Pin<'a>
+    pin: Pin<'a>
 }
 
-#[repr(C,packed)]
+pub struct Rts<'a> {
+    uart: u8,
+    pin: Pin<'a>
+}
+
+pub struct Cts<'a> {
+    uart: u8,
+    pin: Pin<'a>
+}
+
+pub struct EwmOut<'a> {
+    pin: Pin<'a>
+}
+
+#[repr(C)]
 struct GpioBitband {
     pdor: [Volatile<u32>; 32],
     psor: [Volatile<u32>; 32],
@@ -60,29 +95,52 @@ pub struct Gpio<'a> {
 impl Port {
     pub unsafe fn new(name: PortName, gate: ClockGate) -> Port {
         let myself = &mut * match name {
+            PortName::A => 0x40049000 as *mut PortRegs,
             PortName::B => 0x4004A000 as *mut PortRegs,
-            PortName::C => 0x4004B000 as *mut PortRegs
+            PortName::C => 0x4004B000 as *mut PortRegs,
+            PortName::D => 0x4004C000 as *mut PortRegs,
+            PortName::E => 0x4004D000 as *mut PortRegs
         };
 
-        Port { reg: UnsafeCell::new(myself), locks: Default::default(), _gate: gate }
+        Port::from_regs(name, myself, gpio_bitband(name), gate)
+    }
+
+    // Tests hand in their own register blocks here.
+    unsafe fn from_regs(name: PortName, reg: &'static mut PortRegs, gpio: *mut GpioBitband, gate: ClockGate) -> Port {
+        Port { reg: UnsafeCell::new(reg), gpio, name, roles: Default::default(), _gate: gate }
     }
 
-    pub fn pin(&self, p: usize) -> Pin {
+    pub fn pin(&self, p: usize) -> Pin<'_> {
         assert!(p < 32);
-        let was_init = self.locks[p].swap(true, Ordering::Relaxed);
-        if was_init {
-            panic!("Pin {} is already in use", p);
+        let claimed = self.roles[p].compare_exchange(PinRole::Free.encode(),
+                                                     PinRole::Unassigned.encode(),
+                                                     Ordering::Relaxed,
+                                                     Ordering::Relaxed);
+        if let Err(owner) = claimed {
+            panic!("Pin PT{}{} is already in use as {}", self.name(), p, PinRole::decode(owner));
         }
         Pin { port: self, pin: p }
     }
 
-    pub fn name(&self) -> PortName {
-        let addr = (self.reg() as *const PortRegs) as u32;
-        match addr {
-            0x4004A000 => PortName::B,
-            0x4004B000 => PortName::C,
-            _ => unreachable!()
+    pub fn role(&self, p: usize) -> PinRole {
+        assert!(p < 32);
+        PinRole::decode(self.roles[p].load(Ordering::Relaxed))
+    }
+
+    pub fn mux(&self, p: usize) -> u32 {
+        assert!(p < 32);
+        self.reg().pcr[p].read().get_bits(8..11)
+    }
+
+    pub fn write_diagnostics<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        for p in 0..32 {
+            writeln!(w, "PT{}{}: mux {}, {}", self.name(), p, self.mux(p), self.role(p))?;
         }
+        Ok(())
+    }
+
+    pub fn name(&self) -> PortName {
+        self.name
     }
 
     unsafe fn set_pin_mode(&self, p: usize, mode: u32) {
@@ -92,9 +150,23 @@ impl Port {
         });
     }
 
+    unsafe fn disable_pin(&self, p: usize) {
+        assert!(p < 32);
+        // Turn off the output driver before disconnecting the pin, so
+        // that it doesn't come back driving the line the next time
+        // it's muxed to GPIO.
+        (*self.gpio).pddr[p].write(0);
+        self.set_pin_mode(p, 0);
+        self.set_role(p, PinRole::Unassigned);
+    }
+
     unsafe fn drop_pin(&self, p: usize) {
+        self.set_role(p, PinRole::Free);
+    }
+
+    unsafe fn set_role(&self, p: usize, role: PinRole) {
         assert!(p < 32);
-        self.locks[p].store(false, Ordering::Relaxed);
+        self.roles[p].store(role.encode(), Ordering::Relaxed);
     }
 
     fn reg(&self) -> &'static mut PortRegs {
@@ -113,74 +185,385 @@ impl <'a> Pin<'a> {
     pub fn make_gpio(self) -> Gpio<'a> {
         unsafe {
             self.port.set_pin_mode(self.pin, 1);
-            Gpio::new(self.port.name(), self)
+            self.port.set_role(self.pin, PinRole::Gpio);
+            Gpio::new(self)
         }
     }
 
     pub fn make_rx(self) -> Rx<'a> {
+        // (uart, mux) for every pin that can be a UART receiver
+        let (uart, mode) = match (self.port.name(), self.pin) {
+            (PortName::A, 1) => (0, 2),
+            (PortName::B, 16) => (0, 3),
+            (PortName::D, 6) => (0, 3),
+            (PortName::C, 3) => (1, 3),
+            (PortName::E, 1) => (1, 3),
+            (PortName::D, 2) => (2, 3),
+            _ => panic!("Invalid serial RX pin")
+        };
         unsafe {
-            match (self.port.name(), self.pin) {
-                (PortName::B, 16) => {
-                    self.port.set_pin_mode(self.pin, 3);
-                    Rx {_pin: self, uart: 0}
-                },
-                _ => panic!("Invalid serial RX pin")
-            }
+            self.port.set_pin_mode(self.pin, mode);
+            self.port.set_role(self.pin, PinRole::UartRx(uart));
         }
+        Rx {pin: self, uart}
     }
 
     pub fn make_tx(self) -> Tx<'a> {
+        // (uart, mux) for every pin that can be a UART transmitter
+        let (uart, mode) = match (self.port.name(), self.pin) {
+            (PortName::A, 2) => (0, 2),
+            (PortName::B, 17) => (0, 3),
+            (PortName::D, 7) => (0, 3),
+            (PortName::C, 4) => (1, 3),
+            (PortName::E, 0) => (1, 3),
+            (PortName::D, 3) => (2, 3),
+            _ => panic!("Invalid serial TX pin")
+        };
+        unsafe {
+            self.port.set_pin_mode(self.pin, mode);
+            self.port.set_role(self.pin, PinRole::UartTx(uart));
+        }
+        Tx {pin: self, uart}
+    }
+
+    pub fn make_rts(self) -> Rts<'a> {
+        // (uart, mux) for every pin that can be a UART RTS output
+        let (uart, mode) = match (self.port.name(), self.pin) {
+            (PortName::A, 3) => (0, 2),
+            (PortName::B, 2) => (0, 3),
+            (PortName::D, 4) => (0, 3),
+            (PortName::C, 1) => (1, 3),
+            (PortName::D, 0) => (2, 3),
+            _ => panic!("Invalid serial RTS pin")
+        };
+        unsafe {
+            self.port.set_pin_mode(self.pin, mode);
+            self.port.set_role(self.pin, PinRole::UartRts(uart));
+        }
+        Rts {pin: self, uart}
+    }
+
+    pub fn make_cts(self) -> Cts<'a> {
+        // (uart, mux) for every pin that can be a UART CTS input
+        let (uart, mode) = match (self.port.name(), self.pin) {
+            (PortName::A, 0) => (0, 2),
+            (PortName::B, 3) => (0, 3),
+            (PortName::D, 5) => (0, 3),
+            (PortName::C, 2) => (1, 3),
+            (PortName::D, 1) => (2, 3),
+            _ => panic!("Invalid serial CTS pin")
+        };
+        unsafe {
+            self.port.set_pin_mode(self.pin, mode);
+            self.port.set_role(self.pin, PinRole::UartCts(uart));
+        }
+        Cts {pin: self, uart}
+    }
+
+    pub fn make_ewm_out(self) -> EwmOut<'a> {
+        let mode = match (self.port.name(), self.pin) {
+            (PortName::D, 5) => 6,
+            _ => panic!("Invalid EWM_OUT pin")
+        };
         unsafe {
-            match (self.port.name(), self.pin) {
-                (PortName::B, 17) => {
-                    self.port.set_pin_mode(self.pin, 3);
-                    Tx {_pin: self, uart: 0}
-                },
-                _ => panic!("Invalid serial TX pin")
-            }
+            self.port.set_pin_mode(self.pin, mode);
+            self.port.set_role(self.pin, PinRole::EwmOut);
         }
+        EwmOut {pin: self}
     }
 }
 
 impl <'a> Drop for Pin<'a> {
     fn drop(&mut self) {
+        // Whatever role this pin had, dropping it puts it back in its
+        // disabled (analog) state before anyone else can claim it.
         unsafe {
+            self.port.disable_pin(self.pin);
             self.port.drop_pin(self.pin);
         }
     }
 }
 
 impl <'a> Gpio<'a>  {
-    pub unsafe fn new(port: PortName, pin: Pin) -> Gpio {
-        let gpio = match port {
-            PortName::B => 0x43FE0800 as *mut GpioBitband,
-            PortName::C => 0x43FE1000 as *mut GpioBitband
-        };
-
+    pub unsafe fn new(pin: Pin) -> Gpio {
+        let gpio = pin.port.gpio;
         Gpio { gpio, pin }
     }
 
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
+
     pub fn output(&mut self) {
         unsafe {
             (*self.gpio).pddr[self.pin.pin].write(1);
         }
     }
 
+    pub fn input(&mut self) {
+        unsafe {
+            (*self.gpio).pddr[self.pin.pin].write(0);
+        }
+    }
+
     pub fn high(&mut self) {
         unsafe {
             (*self.gpio).psor[self.pin.pin].write(1);
         }
     }
+
+    pub fn low(&mut self) {
+        unsafe {
+            (*self.gpio).pcor[self.pin.pin].write(1);
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        unsafe {
+            (*self.gpio).ptor[self.pin.pin].write(1);
+        }
+    }
+
+    pub fn read(&self) -> bool {
+        unsafe {
+            (*self.gpio).pdir[self.pin.pin].read() != 0
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        unsafe {
+            (*self.gpio).pdor[self.pin.pin].read() != 0
+        }
+    }
+}
+
+impl <'a> ErrorType for Gpio<'a> {
+    type Error = Infallible;
+}
+
+impl <'a> OutputPin for Gpio<'a> {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.low();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.high();
+        Ok(())
+    }
+}
+
+impl <'a> StatefulOutputPin for Gpio<'a> {
+    fn is_set_high(&mut self) -> Result<bool, Infallible> {
+        Ok(self.is_set())
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Infallible> {
+        Ok(!self.is_set())
+    }
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        Gpio::toggle(self);
+        Ok(())
+    }
+}
+
+impl <'a> InputPin for Gpio<'a> {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(self.read())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(!self.read())
+    }
 }
 
 impl <'a> Rx<'a> {
     pub fn uart(&self) -> u8 {
         self.uart
     }
+
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
 }
 
 impl <'a> Tx<'a> {
     pub fn uart(&self) -> u8 {
         self.uart
     }
+
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
+}
+
+impl PinRole {
+    // Roles are packed into a byte so they can live in an atomic:
+    // the high nibble is the kind of role, the low nibble is the
+    // peripheral instance it belongs to.
+    fn encode(self) -> u8 {
+        match self {
+            PinRole::Free => 0x00,
+            PinRole::Unassigned => 0x10,
+            PinRole::Gpio => 0x20,
+            PinRole::UartRx(n) => 0x30 | n,
+            PinRole::UartTx(n) => 0x40 | n,
+            PinRole::UartRts(n) => 0x50 | n,
+            PinRole::UartCts(n) => 0x60 | n,
+            PinRole::EwmOut => 0x70
+        }
+    }
+
+    fn decode(role: u8) -> PinRole {
+        let n = role.get_bits(0..4);
+        match role.get_bits(4..8) {
+            0x0 => PinRole::Free,
+            0x1 => PinRole::Unassigned,
+            0x2 => PinRole::Gpio,
+            0x3 => PinRole::UartRx(n),
+            0x4 => PinRole::UartTx(n),
+            0x5 => PinRole::UartRts(n),
+            0x6 => PinRole::UartCts(n),
+            0x7 => PinRole::EwmOut,
+            _ => unreachable!()
+        }
+    }
+}
+
+impl fmt::Display for PinRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PinRole::Free => write!(f, "free"),
+            PinRole::Unassigned => write!(f, "unassigned"),
+            PinRole::Gpio => write!(f, "GPIO"),
+            PinRole::UartRx(n) => write!(f, "UART{} RX", n),
+            PinRole::UartTx(n) => write!(f, "UART{} TX", n),
+            PinRole::UartRts(n) => write!(f, "UART{} RTS", n),
+            PinRole::UartCts(n) => write!(f, "UART{} CTS", n),
+            PinRole::EwmOut => write!(f, "EWM_OUT")
+        }
+    }
+}
+
+impl fmt::Display for PortName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PortName::A => write!(f, "A"),
+            PortName::B => write!(f, "B"),
+            PortName::C => write!(f, "C"),
+            PortName::D => write!(f, "D"),
+            PortName::E => write!(f, "E")
+        }
+    }
+}
+
+impl <'a> Rts<'a> {
+    pub fn uart(&self) -> u8 {
+        self.uart
+    }
+
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
+}
+
+impl <'a> Cts<'a> {
+    pub fn uart(&self) -> u8 {
+        self.uart
+    }
+
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
+}
+
+impl <'a> EwmOut<'a> {
+    pub fn release(self) -> Pin<'a> {
+        unsafe {
+            self.pin.port.disable_pin(self.pin.pin);
+        }
+        self.pin
+    }
+}
+
+fn gpio_bitband(port: PortName) -> *mut GpioBitband {
+    match port {
+        PortName::A => 0x43FE0000 as *mut GpioBitband,
+        PortName::B => 0x43FE0800 as *mut GpioBitband,
+        PortName::C => 0x43FE1000 as *mut GpioBitband,
+        PortName::D => 0x43FE1800 as *mut GpioBitband,
+        PortName::E => 0x43FE2000 as *mut GpioBitband
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock;
+
+    fn port() -> (Port, *mut GpioBitband) {
+        let gpio = mock::regs::<GpioBitband>() as *mut GpioBitband;
+        let port = unsafe { Port::from_regs(PortName::C, mock::regs(), gpio, ClockGate::mock()) };
+        (port, gpio)
+    }
+
+    #[test]
+    fn set_high_and_low() {
+        let (port, gpio) = port();
+        let mut pin = port.pin(5).make_gpio();
+        pin.set_high().unwrap();
+        assert_eq!(unsafe { (*gpio).psor[5].read() }, 1);
+        assert_eq!(unsafe { (*gpio).pcor[5].read() }, 0);
+        pin.set_low().unwrap();
+        assert_eq!(unsafe { (*gpio).pcor[5].read() }, 1);
+    }
+
+    #[test]
+    fn toggle() {
+        let (port, gpio) = port();
+        let mut pin = port.pin(5).make_gpio();
+        StatefulOutputPin::toggle(&mut pin).unwrap();
+        assert_eq!(unsafe { (*gpio).ptor[5].read() }, 1);
+        assert_eq!(unsafe { (*gpio).ptor[4].read() }, 0);
+    }
+
+    #[test]
+    fn is_set_high() {
+        let (port, gpio) = port();
+        let mut pin = port.pin(5).make_gpio();
+        assert!(!pin.is_set_high().unwrap());
+        assert!(pin.is_set_low().unwrap());
+        unsafe {
+            (*gpio).pdor[5].write(1);
+        }
+        assert!(pin.is_set_high().unwrap());
+        assert!(!pin.is_set_low().unwrap());
+    }
+
+    #[test]
+    fn is_high() {
+        let (port, gpio) = port();
+        let mut pin = port.pin(5).make_gpio();
+        assert!(pin.is_low().unwrap());
+        unsafe {
+            (*gpio).pdir[5].write(1);
+        }
+        assert!(pin.is_high().unwrap());
+    }
 }
@@ -0,0 +1,61 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize,Ordering};
+
+const SIZE: usize = 64;
+
+// A single-producer, single-consumer queue of UART words (up to nine
+// bits each). One side (usually an interrupt handler) only ever
+// pushes and the other only ever pops, so the two indices never have
+// more than one writer.
+pub struct RingBuffer {
+    buf: UnsafeCell<[u16; SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize
+}
+
+unsafe impl Sync for RingBuffer {}
+
+impl Default for RingBuffer {
+    fn default() -> RingBuffer {
+        RingBuffer::new()
+    }
+}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            buf: UnsafeCell::new([0; SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn push(&self, w: u16) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % SIZE;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe {
+            (*self.buf.get())[head] = w;
+        }
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u16> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let w = unsafe {
+            (*self.buf.get())[tail]
+        };
+        self.tail.store((tail + 1) % SIZE, Ordering::Release);
+        Some(w)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
@@ -1,11 +1,9 @@
 use volatile::Volatile;
 use bit_field::BitField;
 
-use core::sync::atomic::{AtomicBool,ATOMIC_BOOL_INIT,Ordering};
+use super::{BaudError,BaudRate,Dma,Ewm,EwmConfig,EwmOut,Mcg,Port,PortName,Rx,Tx,Uart};
 
-use super::{Port,PortName,Rx,Tx,Uart};
-
-#[repr(C,packed)]
+#[repr(C)]
 struct SimRegs {
     sopt1: Volatile<u32>,
     sopt1_cfg: Volatile<u32>,
@@ -41,24 +39,19 @@ pub struct ClockGate {
     gate: &'static mut Volatile<u32>
 }
 
-static SIM_INIT: AtomicBool = ATOMIC_BOOL_INIT;
-
 impl Sim {
-    pub fn new() -> Sim {
-        let was_init = SIM_INIT.swap(true, Ordering::Relaxed);
-        if was_init {
-            panic!("Cannot initialize SIM: It's already active");
-        }
-        let reg = unsafe {
-            &mut *(0x40047000 as *mut SimRegs)
-        };
+    pub unsafe fn new() -> Sim {
+        let reg = &mut *(0x40047000 as *mut SimRegs);
         Sim {reg}
     }
 
     pub fn port(&mut self, port: PortName) -> Port {
         let gate = match port {
+            PortName::A => ClockGate::new(5, 9),
             PortName::B => ClockGate::new(5, 10),
             PortName::C => ClockGate::new(5, 11),
+            PortName::D => ClockGate::new(5, 12),
+            PortName::E => ClockGate::new(5, 13),
         };
         if gate.gate.read() != 0 {
             panic!("Cannot create Port instance; it is already in use");
@@ -69,9 +62,32 @@ impl Sim {
         }
     }
 
-    pub fn uart<'a, 'b>(&mut self, uart: u8, rx: Option<Rx<'a>>, tx: Option<Tx<'b>>, clkdiv: (u16, u8)) -> Uart<'a, 'b> {
+    pub fn dma(&mut self) -> Dma {
+        let mux_gate = ClockGate::new(6, 1);
+        let dma_gate = ClockGate::new(7, 1);
+        if mux_gate.gate.read() != 0 || dma_gate.gate.read() != 0 {
+            panic!("Cannot create Dma instance; it is already in use");
+        }
+        mux_gate.gate.write(1);
+        dma_gate.gate.write(1);
+        unsafe {
+            Dma::new(mux_gate, dma_gate)
+        }
+    }
+
+    // UART0 and UART1 run from the core clock and UART2 from the bus
+    // clock, so the baud rate is worked out from the clocks as they
+    // are now. Set the clocks up first.
+    pub fn uart<'a, 'b>(&mut self, uart: u8, rx: Option<Rx<'a>>, tx: Option<Tx<'b>>, baud: u32) -> Result<Uart<'a, 'b>, BaudError> {
+        let clock = match uart {
+            0 | 1 => self.core_clock(),
+            _ => self.bus_clock()
+        };
+        let baud = BaudRate::new(baud, clock)?;
         let gate = match uart {
             0 => ClockGate::new(4, 10),
+            1 => ClockGate::new(4, 11),
+            2 => ClockGate::new(4, 12),
             _ => panic!("Cannot enable clock for UART {}", uart)
         };
         if gate.gate.read() != 0 {
@@ -79,7 +95,18 @@ impl Sim {
         }
         gate.gate.write(1);
         unsafe {
-            Uart::new(uart, rx, tx, clkdiv, gate)
+            Ok(Uart::new(uart, rx, tx, baud, gate))
+        }
+    }
+
+    pub fn ewm<'a>(&mut self, out: EwmOut<'a>, config: EwmConfig) -> Ewm<'a> {
+        let gate = ClockGate::new(4, 1);
+        if gate.gate.read() != 0 {
+            panic!("Cannot create Ewm instance; it is already in use");
+        }
+        gate.gate.write(1);
+        unsafe {
+            Ewm::new(out, config, gate)
         }
     }
 
@@ -88,15 +115,15 @@ impl Sim {
         clkdiv.set_bits(28..32, core-1);
         clkdiv.set_bits(24..28, bus-1);
         clkdiv.set_bits(16..20, flash-1);
-        unsafe {
-            self.reg.clkdiv1.write(clkdiv);
-        }
+        self.reg.clkdiv1.write(clkdiv);
     }
-}
 
-impl Drop for Sim {
-    fn drop(&mut self) {
-        SIM_INIT.store(false, Ordering::Relaxed);
+    pub fn core_clock(&self) -> u32 {
+        Mcg::output_hz() / (self.reg.clkdiv1.read().get_bits(28..32) + 1)
+    }
+
+    pub fn bus_clock(&self) -> u32 {
+        Mcg::output_hz() / (self.reg.clkdiv1.read().get_bits(24..28) + 1)
     }
 }
 
@@ -119,3 +146,12 @@ impl Drop for ClockGate {
         self.gate.write(0);
     }
 }
+
+#[cfg(test)]
+impl ClockGate {
+    // A gate backed by ordinary memory, for handing to drivers under
+    // test.
+    pub fn mock() -> ClockGate {
+        ClockGate { gate: Box::leak(Box::new(Volatile::new(1))) }
+    }
+}
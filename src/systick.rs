@@ -0,0 +1,144 @@
+use volatile::Volatile;
+use bit_field::BitField;
+
+use core::cmp;
+
+use embedded_hal::delay::DelayNs;
+
+#[repr(C)]
+struct SysTickRegs {
+    csr: Volatile<u32>,
+    rvr: Volatile<u32>,
+    cvr: Volatile<u32>,
+    calib: Volatile<u32>
+}
+
+pub struct SysTick {
+    reg: &'static mut SysTickRegs
+}
+
+pub struct Delay {
+    systick: SysTick,
+    core_hz: u32
+}
+
+impl SysTick {
+    pub unsafe fn new() -> SysTick {
+        SysTick::from_regs(&mut *(0xE000E010 as *mut SysTickRegs))
+    }
+
+    // Tests hand in their own register block here.
+    unsafe fn from_regs(reg: &'static mut SysTickRegs) -> SysTick {
+        SysTick {reg}
+    }
+
+    // Count down `ticks` core clock cycles. The reload register is
+    // only 24 bits wide, so the caller has to split longer waits.
+    // A reload value of 0 never sets COUNTFLAG, so a single tick is
+    // stretched to two.
+    fn wait(&mut self, ticks: u32) {
+        assert!(ticks > 0 && ticks <= 0x01000000);
+        self.reg.rvr.write(cmp::max(ticks, 2) - 1);
+        self.reg.cvr.write(0);
+        self.reg.csr.update(|csr| {
+            csr.set_bit(2, true); // Use the core clock
+            csr.set_bit(1, false); // No interrupt
+            csr.set_bit(0, true);
+        });
+        while !self.reg.csr.read().get_bit(16) {}
+        self.reg.csr.update(|csr| {
+            csr.set_bit(0, false);
+        });
+    }
+}
+
+impl Delay {
+    pub fn new(systick: SysTick, core_hz: u32) -> Delay {
+        Delay { systick, core_hz }
+    }
+
+    pub fn free(self) -> SysTick {
+        self.systick
+    }
+
+    // Wait for `time` in units of 1/`per_second` seconds, rounding up
+    // to a whole core clock tick.
+    fn wait(&mut self, time: u64, per_second: u64) {
+        let ticks = (time * u64::from(self.core_hz)).div_ceil(per_second);
+        let (reloads, rest) = split_ticks(ticks);
+        for _ in 0..reloads {
+            self.systick.wait(0x01000000);
+        }
+        if rest > 0 {
+            self.systick.wait(rest);
+        }
+    }
+}
+
+// Split a wait into as many full 24-bit reloads as it takes, plus
+// whatever is left over.
+fn split_ticks(ticks: u64) -> (u64, u32) {
+    (ticks / 0x01000000, (ticks % 0x01000000) as u32)
+}
+
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.wait(u64::from(ns), 1_000_000_000);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.wait(u64::from(us), 1_000_000);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.wait(u64::from(ms), 1_000);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock;
+
+    fn delay() -> (Delay, *mut SysTickRegs) {
+        let reg = mock::regs::<SysTickRegs>();
+        // COUNTFLAG is always set, so every wait returns at once.
+        reg.csr.write(1 << 16);
+        let ptr = reg as *mut SysTickRegs;
+        let systick = unsafe { SysTick::from_regs(reg) };
+        (Delay::new(systick, 72_000_000), ptr)
+    }
+
+    #[test]
+    fn split_ticks_at_reload() {
+        assert_eq!(split_ticks(0), (0, 0));
+        assert_eq!(split_ticks(0x00FFFFFF), (0, 0x00FFFFFF));
+        assert_eq!(split_ticks(0x01000000), (1, 0));
+        assert_eq!(split_ticks(3 * 0x01000000 + 5), (3, 5));
+    }
+
+    #[test]
+    fn delay_ns_rounds_up() {
+        let (mut delay, reg) = delay();
+        delay.delay_ns(1000);
+        assert_eq!(unsafe { (*reg).rvr.read() }, 71);
+        // 1ns is a fraction of a tick, which still waits a whole one,
+        // and a one tick wait is stretched to two.
+        delay.delay_ns(1);
+        assert_eq!(unsafe { (*reg).rvr.read() }, 1);
+    }
+
+    #[test]
+    fn long_delay_splits_reloads() {
+        let (mut delay, reg) = delay();
+        // 72,000,000 ticks is four full reloads and 4,891,136 more.
+        delay.delay_ms(1000);
+        assert_eq!(unsafe { (*reg).rvr.read() }, 4_891_135);
+        // Exactly nine reloads ends on a full one.
+        delay.delay_ns(2_097_152_000);
+        assert_eq!(unsafe { (*reg).rvr.read() }, 0x00FFFFFF);
+        // One tick past a full reload is stretched to two as well.
+        delay.delay_ns(233_016_900);
+        assert_eq!(unsafe { (*reg).rvr.read() }, 1);
+    }
+}
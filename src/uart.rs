@@ -2,10 +2,16 @@ use volatile::Volatile;
 use bit_field::BitField;
 
 use core;
+use core::mem;
+use core::sync::atomic::{AtomicU8,AtomicUsize,Ordering};
 
-use super::{ClockGate,Rx,Tx};
+use embedded_hal_nb::serial;
+use embedded_hal_nb::serial::ErrorKind;
+use nb;
 
-#[repr(C,packed)]
+use super::{ClockGate,Cts,DmaChannel,Nvic,ReadTransfer,RingBuffer,Rts,Rx,Tx,without_interrupts};
+
+#[repr(C)]
 struct UartRegs {
     bdh: Volatile<u8>,
     bdl: Volatile<u8>,
@@ -22,17 +28,149 @@ struct UartRegs {
     ed: Volatile<u8>,
     modem: Volatile<u8>,
     ir: Volatile<u8>,
+    _pad0: u8,
+    pfifo: Volatile<u8>,
+    cfifo: Volatile<u8>,
+    sfifo: Volatile<u8>,
+    twfifo: Volatile<u8>,
+    tcfifo: Volatile<u8>,
+    rwfifo: Volatile<u8>,
+    rcfifo: Volatile<u8>,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum UartError {
+    Overrun,
+    Noise,
+    Framing,
+    Parity,
+    Break
+}
+
+// How long a transmitted break is, in bit times (one more with 9-bit
+// frames). This has no effect on break detection, which always takes
+// 11 bit times of low line, or 12 with 9-bit frames.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum BreakLength {
+    Ten,
+    Thirteen
+}
+
+// Most receivers start having trouble somewhere past a few percent
+// of baud mismatch, so refuse anything we can't get reasonably close.
+const MAX_BAUD_ERROR: f32 = 2.0;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct BaudRate {
+    requested: u32,
+    actual: u32,
+    sbr: u16,
+    brfa: u8
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BaudError {
+    TooLow,
+    TooHigh,
+    Tolerance(BaudRate)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd
+}
+
+// Data bits per frame, not counting the parity bit.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum DataBits {
+    Seven,
+    Eight,
+    Nine
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum StopBits {
+    One,
+    Two
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct FrameFormat {
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub invert_tx: bool,
+    pub invert_rx: bool,
+    pub msb_first: bool
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum FormatError {
+    // Seven data bits only exist as "eight bits, one of them parity"
+    SevenBitsWithoutParity,
+    // The MK20DX256 UARTs have no SBNS bit, so they always send one
+    // stop bit. They'll still receive two-stop-bit frames fine with
+    // `StopBits::One`, since only the first stop bit is checked.
+    TwoStopBits
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Mode {
+    Normal,
+    // The transmitter feeds the receiver directly, and RX is ignored
+    Loopback,
+    // TX and RX share the TX pin, see `set_tx_direction`
+    SingleWire
+}
+
+// Whether the idle-line timer starts counting right after the start
+// bit or only after the stop bit. Counting from the stop bit keeps a
+// run of 1 bits at the end of a byte from looking like idle time.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum IdleType {
+    AfterStart,
+    AfterStop
+}
+
+// Width of a transmitted IrDA pulse, as a fraction of a bit time.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum IrdaPulse {
+    ThreeSixteenths = 0,
+    OneSixteenth = 1,
+    OneThirtySecond = 2,
+    OneQuarter = 3
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct FifoStatus {
+    pub tx_overflow: bool,
+    pub rx_overflow: bool,
+    pub rx_underflow: bool
 }
 
 pub struct Uart<'a, 'b> {
     reg: &'static mut UartRegs,
-    _rx: Option<Rx<'a>>,
-    _tx: Option<Tx<'b>>,
+    id: u8,
+    baud: BaudRate,
+    format: FrameFormat,
+    mode: Mode,
+    interrupts: bool,
+    rx: Option<Rx<'a>>,
+    tx: Option<Tx<'b>>,
+    rts: Option<Rts<'a>>,
+    cts: Option<Cts<'b>>,
     _gate: ClockGate
 }
 
 impl <'a, 'b> Uart<'a, 'b> {
-    pub unsafe fn new(id: u8, rx: Option<Rx<'a>>, tx: Option<Tx<'b>>, clkdiv: (u16,u8), gate: ClockGate) -> Uart<'a, 'b> {
+    pub unsafe fn new(id: u8, rx: Option<Rx<'a>>, tx: Option<Tx<'b>>, baud: BaudRate, gate: ClockGate) -> Uart<'a, 'b> {
+        Uart::from_regs(regs(id), id, rx, tx, baud, gate)
+    }
+
+    // Tests hand in their own register block here.
+    unsafe fn from_regs(uart: &'static mut UartRegs, id: u8, rx: Option<Rx<'a>>, tx: Option<Tx<'b>>, baud: BaudRate, gate: ClockGate) -> Uart<'a, 'b> {
         if let Some(r) = rx.as_ref() {
             if r.uart() != id {
                 panic!("Invalid RX pin for UART {}", id);
@@ -43,42 +181,1153 @@ impl <'a, 'b> Uart<'a, 'b> {
                 panic!("Invalid TX pin for UART {}", id);
             }
         }
-        if clkdiv.0 >= 8192 {
-            panic!("Invalid UART clock divider: {}", clkdiv.0);
-        }
-        if clkdiv.1 >= 32 {
-            panic!("Invalid UART fractional divisor: {}", clkdiv.1);
-        }
-
-        let uart = match id {
-            0 => &mut *(0x4006A000 as *mut UartRegs),
-            _ => panic!("Invalid UART id: {}", id)
-        };
 
         uart.c4.update(|c4| {
-            c4.set_bits(0..5, clkdiv.1);
+            c4.set_bits(0..5, baud.brfa);
         });
         uart.bdh.update(|bdh| {
-            bdh.set_bits(0..5, clkdiv.0.get_bits(8..13) as u8);
+            bdh.set_bits(0..5, baud.sbr.get_bits(8..13) as u8);
         });
-        uart.bdl.write(clkdiv.0.get_bits(0..8) as u8);
+        uart.bdl.write(baud.sbr.get_bits(0..8) as u8);
 
         uart.c2.update(|c2| {
             c2.set_bit(2, rx.is_some());
             c2.set_bit(3, tx.is_some());
         });
 
-        Uart {reg: uart, _tx: tx, _rx: rx, _gate: gate}
+        Uart {reg: uart, id, baud, format: FrameFormat::default(), mode: Mode::Normal, interrupts: false, tx, rx, rts: None, cts: None, _gate: gate}
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: FrameFormat) -> Result<(), FormatError> {
+        if format.stop_bits == StopBits::Two {
+            return Err(FormatError::TwoStopBits);
+        }
+
+        // M picks 9-bit frames and M10 stretches that to 10 bits, and
+        // the parity bit (if any) is the last of those.
+        let (m, m10) = match (format.data_bits, format.parity) {
+            (DataBits::Seven, Parity::None) => return Err(FormatError::SevenBitsWithoutParity),
+            (DataBits::Seven, _) => (false, false),
+            (DataBits::Eight, Parity::None) => (false, false),
+            (DataBits::Eight, _) => (true, false),
+            (DataBits::Nine, Parity::None) => (true, false),
+            (DataBits::Nine, _) => (true, true)
+        };
+
+        self.reconfigure(|reg| {
+            reg.c1.update(|c1| {
+                c1.set_bit(4, m);
+                c1.set_bit(1, format.parity != Parity::None);
+                c1.set_bit(0, format.parity == Parity::Odd);
+            });
+            reg.c4.update(|c4| {
+                c4.set_bit(5, m10);
+            });
+            reg.c3.update(|c3| {
+                c3.set_bit(4, format.invert_tx);
+            });
+            update_s2(reg, |s2| {
+                s2.set_bit(5, format.msb_first);
+                s2.set_bit(4, format.invert_rx);
+            });
+        });
+        self.format = format;
+        Ok(())
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        let (rx, tx) = match mode {
+            Mode::Normal => (self.rx.is_some(), self.tx.is_some()),
+            Mode::Loopback => (true, true),
+            Mode::SingleWire => {
+                if self.tx.is_none() {
+                    panic!("UART {} needs a TX pin for single-wire mode", self.id);
+                }
+                (true, true)
+            }
+        };
+
+        self.reconfigure(|reg| {
+            reg.c1.update(|c1| {
+                c1.set_bit(7, mode != Mode::Normal);
+                c1.set_bit(5, mode == Mode::SingleWire);
+            });
+            // Single-wire mode starts out listening.
+            reg.c3.update(|c3| {
+                c3.set_bit(5, false);
+            });
+        });
+        self.reg.c2.update(|c2| {
+            c2.set_bit(2, rx);
+            c2.set_bit(3, tx);
+        });
+        self.mode = mode;
+    }
+
+    // In single-wire mode, choose whether the TX pin is driven
+    // (`true`) or used as the receive input. Make sure a write has
+    // finished with `flush` before turning the line around.
+    pub fn set_tx_direction(&mut self, output: bool) {
+        assert!(self.mode == Mode::SingleWire);
+        self.reg.c3.update(|c3| {
+            c3.set_bit(5, output);
+        });
+    }
+
+    // Loop a few test patterns through the UART and check they come
+    // back intact. This needs no wiring, so it's safe to do at boot.
+    // Loopback mode still drives the TX pin, so for the length of the
+    // test the pin is taken over as a GPIO holding the line idle, to
+    // keep the patterns off it.
+    pub fn self_test(&mut self) -> bool {
+        assert!(!self.interrupts);
+        let mode = self.mode;
+        let idle_high = !self.format.invert_tx;
+        let tx = self.tx.take().map(|tx| {
+            let mut gpio = tx.release().make_gpio();
+            if idle_high {
+                gpio.high();
+            } else {
+                gpio.low();
+            }
+            gpio.output();
+            gpio
+        });
+        self.set_mode(Mode::Loopback);
+
+        // Throw away anything that was already waiting
+        while receive(self.reg).is_some() {}
+
+        let mut passed = true;
+        for &pattern in &[0x55, 0xAA, 0x00, 0xFF] {
+            while tx_room(self.reg) == 0 {}
+            self.reg.d.write(pattern);
+            // The receiver samples the stop bit before the
+            // transmitter is done sending it, so once TC is set the
+            // byte has to be there.
+            while !self.reg.s1.read().get_bit(6) {}
+            match receive(self.reg) {
+                Some(Ok(w)) if w as u8 == pattern => {},
+                _ => passed = false
+            }
+        }
+
+        self.tx = tx.map(|gpio| gpio.release().make_tx());
+        self.set_mode(mode);
+        passed
+    }
+
+    // Hardware flow control. The receiver deasserts RTS when it
+    // can't take any more data, and the transmitter waits while CTS
+    // is deasserted. Either pin can be left out.
+    pub fn enable_flow_control(&mut self, rts: Option<Rts<'a>>, cts: Option<Cts<'b>>) {
+        self.disable_flow_control();
+        if let Some(r) = rts.as_ref() {
+            if r.uart() != self.id {
+                panic!("Invalid RTS pin for UART {}", self.id);
+            }
+        }
+        if let Some(c) = cts.as_ref() {
+            if c.uart() != self.id {
+                panic!("Invalid CTS pin for UART {}", self.id);
+            }
+        }
+
+        self.reg.modem.update(|modem| {
+            modem.set_bit(3, rts.is_some());
+            modem.set_bit(0, cts.is_some());
+        });
+        self.rts = rts;
+        self.cts = cts;
+    }
+
+    // Use RTS as an RS-485 driver enable: it's asserted for as long
+    // as the transmitter is sending, so a half-duplex transceiver is
+    // only driving the bus while we talk.
+    pub fn enable_rs485(&mut self, rts: Rts<'a>, active_high: bool) {
+        self.disable_flow_control();
+        if rts.uart() != self.id {
+            panic!("Invalid RTS pin for UART {}", self.id);
+        }
+
+        self.reg.modem.update(|modem| {
+            modem.set_bit(2, active_high);
+            modem.set_bit(1, true);
+        });
+        self.rts = Some(rts);
+    }
+
+    // Turn off flow control and RS-485 mode, and hand back the pins.
+    pub fn disable_flow_control(&mut self) -> (Option<Rts<'a>>, Option<Cts<'b>>) {
+        self.reg.modem.write(0);
+        (self.rts.take(), self.cts.take())
+    }
+
+    // Queue a break after whatever is currently being sent.
+    pub fn send_break(&mut self) {
+        while !TX_BUFFERS[self.id as usize].is_empty() {}
+        // Toggling SBK queues exactly one break character.
+        unsafe {
+            bitband(&self.reg.c2, 0).write(1);
+            bitband(&self.reg.c2, 0).write(0);
+        }
+    }
+
+    pub fn set_break_length(&mut self, length: BreakLength) {
+        update_s2(self.reg, |s2| {
+            s2.set_bit(2, length == BreakLength::Thirteen);
+        });
+    }
+
+    // With break detection on, a break no longer shows up as a
+    // framing error on a zero byte. Instead the read path reports
+    // `UartError::Break`, as LIN needs to find the start of a frame.
+    // The line has to be low for 11 bit times (12 with 9-bit frames),
+    // whatever the transmit `BreakLength`.
+    pub fn enable_break_detect(&mut self, enable: bool) {
+        update_s2(self.reg, |s2| {
+            s2.set_bit(1, enable);
+        });
+        let interrupts = self.interrupts;
+        self.reg.bdh.update(|bdh| {
+            bdh.set_bit(7, enable && interrupts);
+        });
+    }
+
+    // Blocking 9-bit write. The ninth bit goes out last, in T8.
+    pub fn write9(&mut self, word: u16) {
+        while !TX_BUFFERS[self.id as usize].is_empty() {}
+        while tx_room(self.reg) == 0 {}
+        self.reg.c3.update(|c3| {
+            c3.set_bit(6, word.get_bit(8));
+        });
+        self.reg.d.write(word as u8);
+    }
+
+    // Blocking 9-bit read. The ninth bit comes back as bit 8.
+    pub fn read9(&mut self) -> Result<u16, UartError> {
+        nb::block!(self.try_read9())
+    }
+
+    // Multidrop mode for 9-bit buses: frames with the ninth bit set
+    // are addresses. The receiver drops everything until it sees an
+    // address matching `addr1` or `addr2`, then delivers that address
+    // and the data frames after it, until an address for some other
+    // station comes along. Read with `read9`, `try_read9` or
+    // `serial::Read<u16>`, polled or with interrupts on: the address
+    // is the word with bit 8 set.
+    pub fn enable_address_match(&mut self, addr1: Option<u8>, addr2: Option<u8>) {
+        if self.format.data_bits != DataBits::Nine || self.format.parity != Parity::None {
+            panic!("Address matching needs 9-bit frames without parity");
+        }
+        if addr1.is_none() && addr2.is_none() {
+            panic!("Address matching needs at least one address");
+        }
+
+        self.reconfigure(|reg| {
+            reg.ma1.write(addr1.unwrap_or(0));
+            reg.ma2.write(addr2.unwrap_or(0));
+            reg.c4.update(|c4| {
+                c4.set_bit(7, addr1.is_some());
+                c4.set_bit(6, addr2.is_some());
+            });
+            reg.c1.update(|c1| {
+                c1.set_bit(3, true);
+            });
+        });
+    }
+
+    pub fn disable_address_match(&mut self) {
+        self.reconfigure(|reg| {
+            reg.c4.update(|c4| {
+                c4.set_bit(7, false);
+                c4.set_bit(6, false);
+            });
+            reg.c1.update(|c1| {
+                c1.set_bit(3, false);
+            });
+        });
+    }
+
+    // Select the station that the following data frames are for.
+    pub fn write_address(&mut self, address: u8) {
+        self.write9(0x100 | u16::from(address));
+        // T8 sticks, so put it back once the address frame is out, or
+        // every byte after it would look like an address too.
+        self.flush();
+        self.reg.c3.update(|c3| {
+            c3.set_bit(6, false);
+        });
+    }
+
+    // Encode and decode IrDA SIR pulses. Most IrDA transceivers have
+    // an active-low receive output, which also needs `invert_rx` set
+    // in the frame format.
+    pub fn enable_irda(&mut self, pulse: IrdaPulse) {
+        self.reconfigure(|reg| {
+            reg.ir.update(|ir| {
+                ir.set_bits(0..2, pulse as u8);
+                ir.set_bit(2, true);
+            });
+        });
+    }
+
+    pub fn disable_irda(&mut self) {
+        self.reconfigure(|reg| {
+            reg.ir.write(0);
+        });
+    }
+
+    // Switch to interrupt-driven operation. Received bytes are
+    // collected into a ring buffer by the status IRQ, and writes are
+    // queued for the IRQ to send instead of waiting on the hardware.
+    pub fn enable_interrupts(&mut self, nvic: &mut Nvic) {
+        self.interrupts = true;
+        let break_detect = self.reg.s2.read().get_bit(1);
+        self.reg.c2.update(|c2| {
+            c2.set_bit(5, true);
+        });
+        self.reg.bdh.update(|bdh| {
+            bdh.set_bit(7, break_detect);
+        });
+        self.update_idle_interrupt();
+        nvic.enable(status_irq(self.id));
+    }
+
+    // Return to polled operation, first sending anything still
+    // waiting in the transmit buffer. This doesn't need the NVIC:
+    // with RIE and TIE clear the UART never raises its IRQ.
+    pub fn disable_interrupts(&mut self) {
+        unsafe {
+            bitband(&self.reg.c2, 7).write(0);
+            bitband(&self.reg.c2, 5).write(0);
+            bitband(&self.reg.c2, 4).write(0);
+            bitband(&self.reg.bdh, 7).write(0);
+        }
+        self.interrupts = false;
+        while let Some(b) = TX_BUFFERS[self.id as usize].pop() {
+            while tx_room(self.reg) == 0 {}
+            self.reg.d.write(b as u8);
+        }
+    }
+
+    // The transmit and receive FIFO depths, in bytes. UART2 has no
+    // FIFO, which shows up here as a depth of one.
+    pub fn fifo_size(&self) -> (usize, usize) {
+        let pfifo = self.reg.pfifo.read();
+        (fifo_depth(pfifo.get_bits(4..7)), fifo_depth(pfifo.get_bits(0..3)))
+    }
+
+    // Turn on both FIFOs. The transmitter asks for more data once
+    // the TX FIFO has `tx_watermark` or fewer bytes in it, and the
+    // receiver reports data once `rx_watermark` bytes have arrived.
+    pub fn enable_fifo(&mut self, tx_watermark: u8, rx_watermark: u8) {
+        let (tx_size, rx_size) = self.fifo_size();
+        if tx_size == 1 || rx_size == 1 {
+            panic!("UART {} has no FIFO", self.id);
+        }
+        if tx_watermark as usize >= tx_size {
+            panic!("Invalid TX FIFO watermark: {}", tx_watermark);
+        }
+        if rx_watermark == 0 || rx_watermark as usize > rx_size {
+            panic!("Invalid RX FIFO watermark: {}", rx_watermark);
+        }
+
+        self.set_fifo(true);
+        self.reg.twfifo.write(tx_watermark);
+        self.reg.rwfifo.write(rx_watermark);
+        self.update_idle_interrupt();
+    }
+
+    pub fn disable_fifo(&mut self) {
+        self.set_fifo(false);
+        self.reg.twfifo.write(0);
+        self.reg.rwfifo.write(1);
+        self.update_idle_interrupt();
+    }
+
+    pub fn set_idle_type(&mut self, idle: IdleType) {
+        self.reconfigure(|reg| {
+            reg.c1.update(|c1| {
+                c1.set_bit(2, idle == IdleType::AfterStop);
+            });
+        });
+    }
+
+    // Read one packet into `buf`, where a packet is everything
+    // received before the line next goes idle. Returns the packet's
+    // length, or `buf.len()` if it didn't fit, in which case the rest
+    // is returned by the next call. In 9-bit mode only the low byte of
+    // each word is kept.
+    pub fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, UartError> {
+        assert!(!self.interrupts);
+        let mut len = 0;
+        while len < buf.len() {
+            match self.try_read9() {
+                Ok(w) => {
+                    buf[len] = w as u8;
+                    len += 1;
+                },
+                Err(nb::Error::Other(e)) => return Err(e),
+                Err(nb::Error::WouldBlock) => {
+                    // An idle flag from before the first byte is
+                    // left over from the last packet, so just clear it.
+                    if clear_idle(self.reg, self.id as usize) && len > 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    // Run `callback` from the status IRQ each time the line goes
+    // idle. Combined with a circular `dma_read`, the DMA channel's
+    // position at that point marks the end of a packet. This only
+    // fires while interrupts are enabled.
+    pub fn on_idle(&mut self, callback: Option<fn()>) {
+        let ptr = callback.map(|f| f as usize).unwrap_or(0);
+        IDLE_CALLBACKS[self.id as usize].store(ptr, Ordering::Relaxed);
+        self.update_idle_interrupt();
+    }
+
+    fn update_idle_interrupt(&mut self) {
+        // Bytes below the receive watermark don't raise RDRF, so with
+        // the FIFO on we also wake up on idle to collect them.
+        let fifo = self.reg.pfifo.read().get_bit(3);
+        let callback = IDLE_CALLBACKS[self.id as usize].load(Ordering::Relaxed) != 0;
+        let enable = self.interrupts && (fifo || callback);
+        unsafe {
+            bitband(&self.reg.c2, 4).write(enable as u32);
+        }
+    }
+
+    // Read and clear the FIFO error flags.
+    pub fn fifo_status(&mut self) -> FifoStatus {
+        let sfifo = self.reg.sfifo.read();
+        self.reg.sfifo.write(sfifo & 0x07);
+        FifoStatus {
+            tx_overflow: sfifo.get_bit(1),
+            rx_overflow: sfifo.get_bit(2),
+            rx_underflow: sfifo.get_bit(0)
+        }
+    }
+
+    fn set_fifo(&mut self, enable: bool) {
+        // The FIFOs have to be flushed after they're switched.
+        self.reconfigure(|reg| {
+            reg.pfifo.update(|pfifo| {
+                pfifo.set_bit(7, enable);
+                pfifo.set_bit(3, enable);
+            });
+            reg.cfifo.update(|cfifo| {
+                cfifo.set_bit(7, true);
+                cfifo.set_bit(6, true);
+            });
+        });
+    }
+
+    // Most of the UART's configuration can only be changed while the
+    // transmitter and receiver are off. Let anything already queued
+    // finish, then run `f` with both disabled.
+    fn reconfigure<F: FnOnce(&mut UartRegs)>(&mut self, f: F) {
+        self.flush();
+        let c2 = self.reg.c2.read();
+        self.reg.c2.update(|c2| {
+            c2.set_bit(2, false);
+            c2.set_bit(3, false);
+        });
+        f(self.reg);
+        self.reg.c2.write(c2);
+    }
+
+    // Send `data` using a DMA channel. `on_complete` runs from the
+    // DMA interrupt once the last byte has been handed to the UART.
+    // That interrupt also gives TDRE back to the CPU, so it's enabled
+    // here even without a callback.
+    pub fn dma_write(&mut self, channel: &mut DmaChannel, data: &'static [u8], on_complete: Option<fn()>, nvic: &mut Nvic) {
+        let d = &self.reg.d as *const Volatile<u8> as *mut u8;
+        channel.set_source(dma_source(self.id) + 1);
+        unsafe {
+            channel.memory_to_peripheral(data.as_ptr(), d, data.len());
+        }
+        let ptr = on_complete.map(|f| f as usize).unwrap_or(0);
+        TX_DMA_CALLBACKS[self.id as usize].store(ptr, Ordering::Relaxed);
+        channel.on_complete(Some(match self.id {
+            0 => uart0_tx_dma,
+            1 => uart1_tx_dma,
+            _ => uart2_tx_dma
+        }));
+        nvic.enable(channel.channel());
+        channel.start();
+        // TDRE turns into a DMA request rather than an interrupt.
+        self.reg.c5.update(|c5| {
+            c5.set_bit(7, true);
+        });
+        unsafe {
+            bitband(&self.reg.c2, 7).write(1);
+        }
+    }
+
+    // Receive into `buf` using a DMA channel. A circular transfer
+    // keeps overwriting `buf` from the start, calling `on_complete`
+    // at the halfway point and at the end of each pass. The returned
+    // transfer shows what has arrived so far, and gives `buf` back
+    // once stopped (follow that with `stop_dma`).
+    pub fn dma_read<'c, 'd: 'c>(&mut self, channel: &'c mut DmaChannel<'d>, buf: &'static mut [u8], circular: bool, on_complete: Option<fn()>, nvic: &mut Nvic) -> ReadTransfer<'c, 'd> {
+        let d = &self.reg.d as *const Volatile<u8>;
+        channel.set_source(dma_source(self.id));
+        unsafe {
+            channel.peripheral_to_memory(d as *const u8, buf.as_mut_ptr(), buf.len(), circular);
+        }
+        channel.on_complete(on_complete);
+        nvic.enable(channel.channel());
+        channel.start();
+        self.reg.c5.update(|c5| {
+            c5.set_bit(5, true);
+        });
+        unsafe {
+            bitband(&self.reg.c2, 5).write(1);
+            ReadTransfer::new(channel, buf, circular)
+        }
+    }
+
+    // Hand TDRE and RDRF back to the CPU once DMA is finished with
+    // them.
+    pub fn stop_dma(&mut self) {
+        let queued = !TX_BUFFERS[self.id as usize].is_empty();
+        unsafe {
+            bitband(&self.reg.c2, 7).write(queued as u32);
+            bitband(&self.reg.c2, 5).write(self.interrupts as u32);
+        }
+        self.reg.c5.update(|c5| {
+            c5.set_bit(7, false);
+            c5.set_bit(5, false);
+        });
+    }
+
+    // Wait until everything written so far has gone out on the wire.
+    // Writes only wait for room in the data register or FIFO, so this
+    // is needed before anything that cares about the line itself,
+    // like resetting the chip.
+    pub fn flush(&mut self) {
+        while !TX_BUFFERS[self.id as usize].is_empty() {}
+        while !self.reg.s1.read().get_bit(6) {}
+    }
+
+    // Queue as many bytes as can be sent without waiting, and return
+    // how many that was.
+    pub fn try_write(&mut self, data: &[u8]) -> usize {
+        if !self.interrupts {
+            let sent = core::cmp::min(tx_room(self.reg), data.len());
+            for &b in &data[..sent] {
+                self.reg.d.write(b);
+            }
+            return sent;
+        }
+
+        let tx = &TX_BUFFERS[self.id as usize];
+        let mut queued = 0;
+        for &b in data {
+            if !tx.push(u16::from(b)) {
+                break;
+            }
+            queued += 1;
+        }
+        // The IRQ turns TIE off once it runs out of data. The bit is
+        // set through the bit-band alias so we can't race with it.
+        unsafe {
+            bitband(&self.reg.c2, 7).write(1);
+        }
+        queued
+    }
+
+    pub fn baud(&self) -> BaudRate {
+        self.baud
+    }
+
+    pub fn read(&mut self) -> Result<u8, UartError> {
+        nb::block!(self.try_read())
+    }
+
+    // In 9-bit mode this only returns the low byte, so use `read9` or
+    // `try_read9` to see the ninth bit.
+    pub fn try_read(&mut self) -> nb::Result<u8, UartError> {
+        self.try_read9().map(|w| w as u8)
+    }
+
+    pub fn try_read9(&mut self) -> nb::Result<u16, UartError> {
+        // Anything the IRQ already collected comes first, even if
+        // we've since gone back to polling.
+        let id = self.id as usize;
+        if let Some(err) = UartError::from_flags(RX_ERRORS[id].swap(0, Ordering::Relaxed)) {
+            return Err(nb::Error::Other(err));
+        }
+        if let Some(w) = RX_BUFFERS[id].pop() {
+            return Ok(w);
+        }
+        if self.interrupts {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        match receive(self.reg) {
+            Some(Ok(w)) => Ok(w),
+            Some(Err(e)) => Err(nb::Error::Other(e)),
+            None => Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+static TX_BUFFERS: [RingBuffer; 3] = [RingBuffer::new(), RingBuffer::new(), RingBuffer::new()];
+static RX_BUFFERS: [RingBuffer; 3] = [RingBuffer::new(), RingBuffer::new(), RingBuffer::new()];
+static RX_ERRORS: [AtomicU8; 3] = [AtomicU8::new(0), AtomicU8::new(0), AtomicU8::new(0)];
+static IDLE_CALLBACKS: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+static TX_DMA_CALLBACKS: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+unsafe fn regs(id: u8) -> &'static mut UartRegs {
+    match id {
+        0 => &mut *(0x4006A000 as *mut UartRegs),
+        1 => &mut *(0x4006B000 as *mut UartRegs),
+        2 => &mut *(0x4006C000 as *mut UartRegs),
+        _ => panic!("Invalid UART id: {}", id)
+    }
+}
+
+fn fifo_depth(size: u8) -> usize {
+    if size == 0 {
+        1
+    } else {
+        1 << (size + 1)
+    }
+}
+
+// How many bytes can be written to D right now without waiting.
+fn tx_room(reg: &UartRegs) -> usize {
+    let pfifo = reg.pfifo.read();
+    if pfifo.get_bit(7) {
+        fifo_depth(pfifo.get_bits(4..7)) - reg.tcfifo.read() as usize
+    } else if reg.s1.read().get_bit(7) {
+        1
+    } else {
+        0
+    }
+}
+
+// The UART's receive request source in the DMA MUX. Transmit is the
+// next one up.
+fn dma_source(id: u8) -> u8 {
+    2 + 2 * id
+}
+
+fn status_irq(id: u8) -> usize {
+    45 + 2 * id as usize
+}
+
+// Returns the bit-band alias for a single bit of a UART register,
+// so it can be changed without a read-modify-write.
+unsafe fn bitband(reg: &Volatile<u8>, bit: usize) -> &'static mut Volatile<u32> {
+    let addr = reg as *const Volatile<u8> as usize;
+    &mut *((0x42000000 + (addr - 0x40000000) * 32 + bit * 4) as *mut Volatile<u32>)
+}
+
+// Pull the next received byte out of the data register, if there is
+// one.
+fn receive(reg: &mut UartRegs) -> Option<Result<u16, UartError>> {
+    if reg.s2.read().get_bit(7) {
+        update_s2(reg, |s2| {
+            s2.set_bit(7, true);
+        });
+        return Some(Err(UartError::Break));
+    }
+
+    let s1 = reg.s1.read();
+    if !rx_available(reg, s1) && !s1.get_bit(3) {
+        return None;
+    }
+
+    // Reading S1 followed by D clears the receive and error flags,
+    // so we always read D even when the byte is bad. On overrun the
+    // byte in D is valid, but the ones after it were lost, so we drop
+    // it too rather than pretend the stream is intact. In 9-bit mode
+    // R8 has to be read before D.
+    let r8 = reg.c3.read().get_bit(7);
+    let data = (u16::from(r8) << 8 | u16::from(reg.d.read())) & frame_mask(reg);
+    Some(if s1.get_bit(3) {
+        Err(UartError::Overrun)
+    } else if s1.get_bit(1) && data == 0 {
+        // An all-zero frame with no stop bit is a break.
+        Err(UartError::Break)
+    } else if s1.get_bit(1) {
+        Err(UartError::Framing)
+    } else if s1.get_bit(0) {
+        Err(UartError::Parity)
+    } else if s1.get_bit(2) {
+        Err(UartError::Noise)
+    } else {
+        Ok(data)
+    })
+}
+
+// The data bits of a received word. The parity bit is the last bit
+// of the frame, so it's in D[7] with 7 data bits, and in R8 with 8,
+// where R8 is otherwise meaningless. Only 9-bit frames, with parity
+// moved out to the tenth bit by M10 if there is any, use R8 for data.
+fn frame_mask(reg: &UartRegs) -> u16 {
+    let c1 = reg.c1.read();
+    match (c1.get_bit(4), c1.get_bit(1), reg.c4.read().get_bit(5)) {
+        (false, true, _) => 0x7F,
+        (true, false, _) | (true, true, true) => 0x1FF,
+        _ => 0xFF
+    }
+}
+
+// With the FIFO on, RDRF only means the watermark was reached, so we
+// look at the count directly. Reading D from an empty FIFO would be an
+// underflow.
+fn rx_available(reg: &UartRegs, s1: u8) -> bool {
+    if reg.pfifo.read().get_bit(3) {
+        reg.rcfifo.read() > 0
+    } else {
+        s1.get_bit(5)
+    }
+}
+
+// Clear the idle flag, returning whether it was set. The flag is
+// cleared by reading D, so this only goes ahead once everything has
+// been received. A byte can still arrive between checking that and
+// reading D. Without the FIFO it stays in D with RDRF set, since S1
+// was read before it got there. With the FIFO on, the read takes it,
+// so it's queued for the next read instead. Otherwise the read found
+// the FIFO empty, and the underflow leaves the FIFO out of step until
+// it's flushed. A byte finishing in the few cycles between that read
+// and the flush is lost.
+fn clear_idle(reg: &mut UartRegs, id: usize) -> bool {
+    without_interrupts(|| {
+        let s1 = reg.s1.read();
+        if !s1.get_bit(4) || rx_available(reg, s1) {
+            return false;
+        }
+        let r8 = reg.c3.read().get_bit(7);
+        let d = reg.d.read();
+        if reg.pfifo.read().get_bit(3) {
+            if reg.sfifo.read().get_bit(0) {
+                reg.cfifo.update(|cfifo| {
+                    cfifo.set_bit(6, true);
+                });
+                reg.sfifo.write(1 << 0);
+            } else {
+                let word = (u16::from(r8) << 8 | u16::from(d)) & frame_mask(reg);
+                if !RX_BUFFERS[id].push(word) {
+                    RX_ERRORS[id].fetch_or(UartError::Overrun.flag(), Ordering::Relaxed);
+                }
+            }
+        }
+        true
+    })
+}
+
+// The top two bits of S2 are write-1-to-clear flags, so they're
+// masked out unless `f` sets them on purpose.
+fn update_s2<F: FnOnce(&mut u8)>(reg: &mut UartRegs, f: F) {
+    let mut s2 = reg.s2.read() & 0x3F;
+    f(&mut s2);
+    reg.s2.write(s2);
+}
+
+unsafe fn status_isr(id: u8) {
+    let reg = regs(id);
+    let id = id as usize;
+    // While DMA owns a direction, its requests look like our status
+    // flags, so we keep our hands off.
+    let rx_dma = reg.c5.read().get_bit(5);
+    let tx_dma = reg.c5.read().get_bit(7);
+
+    while let Some(result) = if rx_dma { None } else { receive(reg) } {
+        match result {
+            Ok(w) => {
+                if !RX_BUFFERS[id].push(w) {
+                    RX_ERRORS[id].fetch_or(UartError::Overrun.flag(), Ordering::Relaxed);
+                }
+            },
+            Err(e) => {
+                RX_ERRORS[id].fetch_or(e.flag(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    if reg.c2.read().get_bit(4) && clear_idle(reg, id) {
+        let callback = IDLE_CALLBACKS[id].load(Ordering::Relaxed);
+        if callback != 0 {
+            let callback: fn() = mem::transmute(callback);
+            callback();
+        }
+    }
+
+    if reg.c2.read().get_bit(7) && !tx_dma {
+        for _ in 0..tx_room(reg) {
+            match TX_BUFFERS[id].pop() {
+                Some(b) => reg.d.write(b as u8),
+                None => {
+                    bitband(&reg.c2, 7).write(0);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Runs from the DMA interrupt when a `dma_write` finishes. TDMAS
+// would otherwise keep TDRE away from the status IRQ, and anything
+// queued by interrupt-mode writes in the meantime would never go out.
+unsafe fn tx_dma_done(id: u8) {
+    let reg = regs(id);
+    let id = id as usize;
+    bitband(&reg.c5, 7).write(0);
+    bitband(&reg.c2, 7).write(!TX_BUFFERS[id].is_empty() as u32);
+    let callback = TX_DMA_CALLBACKS[id].load(Ordering::Relaxed);
+    if callback != 0 {
+        let callback: fn() = mem::transmute(callback);
+        callback();
+    }
+}
+
+fn uart0_tx_dma() {
+    unsafe {
+        tx_dma_done(0);
+    }
+}
+
+fn uart1_tx_dma() {
+    unsafe {
+        tx_dma_done(1);
+    }
+}
+
+fn uart2_tx_dma() {
+    unsafe {
+        tx_dma_done(2);
+    }
+}
+
+pub extern "C" fn uart0_status() {
+    unsafe {
+        status_isr(0);
+    }
+}
+
+pub extern "C" fn uart1_status() {
+    unsafe {
+        status_isr(1);
+    }
+}
+
+pub extern "C" fn uart2_status() {
+    unsafe {
+        status_isr(2);
     }
 }
 
 impl <'a, 'b> core::fmt::Write for Uart<'a, 'b> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // In interrupt mode we never wait: whatever doesn't fit in
+        // the transmit buffer is dropped and reported as an error.
+        if self.interrupts {
+            let bytes = s.as_bytes();
+            if self.try_write(bytes) != bytes.len() {
+                return Err(core::fmt::Error);
+            }
+            return Ok(());
+        }
+
         for b in s.bytes() {
-            while !self.reg.s1.read().get_bit(7) {}
+            while tx_room(self.reg) == 0 {}
             self.reg.d.write(b);
         }
-        while !self.reg.s1.read().get_bit(6) {}
         Ok(())
     }
 }
+
+impl BaudRate {
+    pub fn new(baud: u32, clock: u32) -> Result<BaudRate, BaudError> {
+        if baud == 0 {
+            return Err(BaudError::TooLow);
+        }
+
+        // The UART divides its source clock by 16 * (SBR + BRFA/32).
+        // Counting in 32nds, that whole divisor is 2 * clock / baud.
+        let divisor = (2 * u64::from(clock) + u64::from(baud) / 2) / u64::from(baud);
+        let sbr = divisor / 32;
+        let brfa = divisor % 32;
+        if sbr == 0 {
+            return Err(BaudError::TooHigh);
+        }
+        if sbr >= 8192 {
+            return Err(BaudError::TooLow);
+        }
+
+        let rate = BaudRate {
+            requested: baud,
+            actual: ((2 * u64::from(clock)) / divisor) as u32,
+            sbr: sbr as u16,
+            brfa: brfa as u8
+        };
+        if rate.error() > MAX_BAUD_ERROR {
+            return Err(BaudError::Tolerance(rate));
+        }
+        Ok(rate)
+    }
+
+    pub fn requested(&self) -> u32 {
+        self.requested
+    }
+
+    pub fn actual(&self) -> u32 {
+        self.actual
+    }
+
+    // The difference between the requested and actual baud, as a
+    // percentage of the requested rate.
+    pub fn error(&self) -> f32 {
+        let diff = self.actual.abs_diff(self.requested);
+        diff as f32 * 100.0 / self.requested as f32
+    }
+}
+
+impl Default for FrameFormat {
+    fn default() -> FrameFormat {
+        FrameFormat {
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            invert_tx: false,
+            invert_rx: false,
+            msb_first: false
+        }
+    }
+}
+
+impl UartError {
+    fn flag(self) -> u8 {
+        match self {
+            UartError::Overrun => 1 << 0,
+            UartError::Noise => 1 << 1,
+            UartError::Framing => 1 << 2,
+            UartError::Parity => 1 << 3,
+            UartError::Break => 1 << 4
+        }
+    }
+
+    fn from_flags(flags: u8) -> Option<UartError> {
+        if flags.get_bit(4) {
+            Some(UartError::Break)
+        } else if flags.get_bit(0) {
+            Some(UartError::Overrun)
+        } else if flags.get_bit(2) {
+            Some(UartError::Framing)
+        } else if flags.get_bit(3) {
+            Some(UartError::Parity)
+        } else if flags.get_bit(1) {
+            Some(UartError::Noise)
+        } else {
+            None
+        }
+    }
+}
+
+impl serial::Error for UartError {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            UartError::Overrun => ErrorKind::Overrun,
+            UartError::Noise => ErrorKind::Noise,
+            UartError::Framing => ErrorKind::FrameFormat,
+            UartError::Parity => ErrorKind::Parity,
+            UartError::Break => ErrorKind::Other
+        }
+    }
+}
+
+impl <'a, 'b> serial::ErrorType for Uart<'a, 'b> {
+    type Error = UartError;
+}
+
+impl <'a, 'b> serial::Read<u8> for Uart<'a, 'b> {
+    fn read(&mut self) -> nb::Result<u8, UartError> {
+        self.try_read()
+    }
+}
+
+impl <'a, 'b> serial::Read<u16> for Uart<'a, 'b> {
+    fn read(&mut self) -> nb::Result<u16, UartError> {
+        self.try_read9()
+    }
+}
+
+impl <'a, 'b> serial::Write<u8> for Uart<'a, 'b> {
+    fn write(&mut self, word: u8) -> nb::Result<(), UartError> {
+        if self.try_write(&[word]) == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), UartError> {
+        if !TX_BUFFERS[self.id as usize].is_empty() || !self.reg.s1.read().get_bit(6) {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock;
+    use embedded_hal_nb::serial::{Read,Write};
+
+    fn uart() -> (Uart<'static, 'static>, *mut UartRegs) {
+        let reg = mock::regs::<UartRegs>();
+        let ptr = reg as *mut UartRegs;
+        let baud = BaudRate::new(115_200, 72_000_000).unwrap();
+        let uart = unsafe { Uart::from_regs(reg, 0, None, None, baud, ClockGate::mock()) };
+        (uart, ptr)
+    }
+
+    #[test]
+    fn write_would_block() {
+        let (mut uart, reg) = uart();
+        assert_eq!(Write::write(&mut uart, 0x42), Err(nb::Error::WouldBlock));
+        unsafe {
+            (*reg).s1.write(1 << 7);
+        }
+        assert_eq!(Write::write(&mut uart, 0x42), Ok(()));
+        assert_eq!(unsafe { (*reg).d.read() }, 0x42);
+    }
+
+    #[test]
+    fn flush_waits_for_tc() {
+        let (mut uart, reg) = uart();
+        assert_eq!(Write::flush(&mut uart), Err(nb::Error::WouldBlock));
+        unsafe {
+            (*reg).s1.write(1 << 6);
+        }
+        assert_eq!(Write::flush(&mut uart), Ok(()));
+    }
+
+    #[test]
+    fn read_would_block() {
+        let (mut uart, reg) = uart();
+        assert_eq!(Read::<u8>::read(&mut uart), Err(nb::Error::WouldBlock));
+        unsafe {
+            (*reg).s1.write(1 << 5);
+            (*reg).d.write(0x42);
+        }
+        assert_eq!(Read::<u8>::read(&mut uart), Ok(0x42));
+    }
+
+    #[test]
+    fn read9_keeps_ninth_bit() {
+        let (mut uart, reg) = uart();
+        unsafe {
+            (*reg).s1.write(1 << 6);
+        }
+        let format = FrameFormat { data_bits: DataBits::Nine, ..FrameFormat::default() };
+        uart.set_format(format).unwrap();
+        unsafe {
+            (*reg).s1.write(1 << 5);
+            (*reg).c3.write(1 << 7);
+            (*reg).d.write(0x42);
+        }
+        assert_eq!(Read::<u16>::read(&mut uart), Ok(0x142));
+    }
+
+    #[test]
+    fn read_masks_to_frame() {
+        let cases = [
+            (DataBits::Seven, Parity::Even, 0x042),
+            (DataBits::Eight, Parity::None, 0x0C2),
+            (DataBits::Eight, Parity::Odd, 0x0C2),
+            (DataBits::Nine, Parity::None, 0x1C2),
+            (DataBits::Nine, Parity::Even, 0x1C2)
+        ];
+        for &(data_bits, parity, word) in &cases {
+            let (mut uart, reg) = uart();
+            unsafe {
+                (*reg).s1.write(1 << 6);
+            }
+            let format = FrameFormat { data_bits, parity, ..FrameFormat::default() };
+            uart.set_format(format).unwrap();
+            unsafe {
+                (*reg).s1.write(1 << 5);
+                (*reg).c3.write(1 << 7);
+                (*reg).d.write(0xC2);
+            }
+            assert_eq!(Read::<u16>::read(&mut uart), Ok(word));
+        }
+    }
+
+    #[test]
+    fn read_in_9_bit_mode_returns_low_byte() {
+        let (mut uart, reg) = uart();
+        unsafe {
+            (*reg).s1.write(1 << 6);
+        }
+        let format = FrameFormat { data_bits: DataBits::Nine, ..FrameFormat::default() };
+        uart.set_format(format).unwrap();
+        unsafe {
+            (*reg).s1.write(1 << 5);
+            (*reg).c3.write(1 << 7);
+            (*reg).d.write(0x42);
+        }
+        assert_eq!(Read::<u8>::read(&mut uart), Ok(0x42));
+    }
+
+    #[test]
+    fn clear_idle_keeps_a_late_byte() {
+        // UART 2 keeps this test's receive buffer away from the others.
+        let reg = mock::regs::<UartRegs>();
+        reg.pfifo.write(1 << 3);
+        reg.s1.write(1 << 4);
+        reg.d.write(0x42);
+        assert!(clear_idle(reg, 2));
+        assert_eq!(RX_BUFFERS[2].pop(), Some(0x42));
+        assert_eq!(reg.cfifo.read(), 0);
+    }
+
+    #[test]
+    fn clear_idle_flushes_after_underflow() {
+        let reg = mock::regs::<UartRegs>();
+        reg.pfifo.write(1 << 3);
+        reg.s1.write(1 << 4);
+        reg.sfifo.write(1 << 0);
+        assert!(clear_idle(reg, 1));
+        assert_eq!(RX_BUFFERS[1].pop(), None);
+        assert!(reg.cfifo.read().get_bit(6));
+    }
+
+    #[test]
+    fn read_errors() {
+        let (mut uart, reg) = uart();
+        let cases = [
+            (1 << 3, 0x42, UartError::Overrun),
+            (1 << 5 | 1 << 2, 0x42, UartError::Noise),
+            (1 << 5 | 1 << 1, 0x42, UartError::Framing),
+            (1 << 5 | 1 << 1, 0x00, UartError::Break),
+            (1 << 5 | 1 << 0, 0x42, UartError::Parity)
+        ];
+        for &(s1, d, err) in &cases {
+            unsafe {
+                (*reg).s1.write(s1);
+                (*reg).d.write(d);
+            }
+            assert_eq!(Read::<u8>::read(&mut uart), Err(nb::Error::Other(err)));
+        }
+    }
+}
@@ -1,8 +1,21 @@
 use volatile::Volatile;
 use bit_field::BitField;
 
-#[repr(C,packed)]
-pub struct Watchdog {
+use core::arch::asm;
+#[cfg(not(test))]
+use core::arch::naked_asm;
+use core::cmp;
+use core::fmt;
+use core::ptr;
+use core::str;
+use core::sync::atomic::{AtomicBool,Ordering};
+
+use super::{Nvic,without_interrupts};
+#[cfg(not(test))]
+use super::Interrupt;
+
+#[repr(C)]
+struct WatchdogRegs {
     stctrlh: Volatile<u16>,
     stctrll: Volatile<u16>,
     tovalh: Volatile<u16>,
@@ -17,20 +30,410 @@ pub struct Watchdog {
     presc: Volatile<u16>
 }
 
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum WatchdogClock {
+    // The 1kHz low power oscillator
+    Lpo,
+    // The bus clock, at the given frequency in Hz
+    Bus(u32)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct WatchdogConfig {
+    pub clock: WatchdogClock,
+    pub timeout_ms: u32,
+    // In window mode, feeding the watchdog sooner than this after the
+    // last feed also resets the chip.
+    pub window_ms: Option<u32>,
+    pub run_in_debug: bool,
+    pub run_in_stop: bool,
+    pub run_in_wait: bool
+}
+
+pub struct Watchdog {
+    reg: &'static mut WatchdogRegs
+}
+
+// What the code was doing when the watchdog ran out: the exception
+// frame the CPU pushed when the watchdog interrupt fired, and the
+// stack words above it.
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct CrashRecord {
+    magic: u32,
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    pub sp: u32,
+    pub stack: [u32; 8],
+    // The supervised task that stopped checking in, if that's why the
+    // watchdog wasn't fed. The name is copied in, since whatever it
+    // pointed to may not survive the reset.
+    task_name: [u8; TASK_NAME_LEN],
+    task_len: u32
+}
+
+// Longer task names are cut short in the crash record.
+const TASK_NAME_LEN: usize = 16;
+
+const CRASH_MAGIC: u32 = 0xDEADD06E;
+
+// Lives in .noinit so that it's still there after the reset.
+#[link_section = ".noinit"]
+static mut CRASH_RECORD: CrashRecord = CrashRecord {
+    magic: 0,
+    r0: 0, r1: 0, r2: 0, r3: 0, r12: 0, lr: 0, pc: 0, xpsr: 0, sp: 0,
+    stack: [0; 8],
+    task_name: [0; TASK_NAME_LEN],
+    task_len: 0
+};
+
+const MAX_TASKS: usize = 8;
+
+#[derive(Clone,Copy)]
+struct SupervisedTask {
+    name: &'static str,
+    deadline_ms: u32,
+    last_seen: Option<u32>
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct TaskId(usize);
+
+// Feeds the watchdog on behalf of several tasks, but only while every
+// one of them has checked in within its own deadline. If any task
+// stops checking in, the watchdog is left to run out and the crash
+// record names the task.
+pub struct Supervisor {
+    tasks: [Option<SupervisedTask>; MAX_TASKS],
+    checkins: [AtomicBool; MAX_TASKS]
+}
+
+extern "C" {
+    fn _stack_top();
+}
+
 impl Watchdog {
-    pub unsafe fn new() -> &'static mut Watchdog {
-        &mut *(0x40052000 as *mut Watchdog)
+    pub unsafe fn new() -> Watchdog {
+        let reg = &mut *(0x40052000 as *mut WatchdogRegs);
+        Watchdog {reg}
     }
 
     pub fn disable(&mut self) {
-        unsafe {
-            self.unlock.write(0xC520);
-            self.unlock.write(0xD928);
-            asm!("nop" : : : "memory");
-            asm!("nop" : : : "memory");
-            self.stctrlh.update(|ctrl| {
+        without_interrupts(|| {
+            self.unlock();
+            self.reg.stctrlh.update(|ctrl| {
                 ctrl.set_bit(0, false);
             });
+        });
+    }
+
+    pub fn configure(&mut self, config: WatchdogConfig) {
+        let hz = match config.clock {
+            WatchdogClock::Lpo => 1000,
+            WatchdogClock::Bus(hz) => hz
+        };
+        let ticks = |ms: u32| u64::from(ms) * u64::from(hz) / 1000;
+
+        // Use the smallest prescaler that lets the timeout fit in the
+        // 32-bit timer.
+        let timeout = ticks(config.timeout_ms);
+        let presc = (0..8).find(|p| timeout / (p + 1) <= u64::from(u32::MAX))
+            .unwrap_or_else(|| panic!("Watchdog timeout too long: {}ms", config.timeout_ms));
+        let toval = (timeout / (presc + 1)) as u32;
+        // The hardware needs a few ticks to do the refresh itself.
+        if toval < 4 {
+            panic!("Watchdog timeout too short: {}ms", config.timeout_ms);
+        }
+
+        let win = match config.window_ms {
+            Some(ms) if ms >= config.timeout_ms => panic!("Watchdog window must be shorter than the timeout"),
+            Some(ms) => (ticks(ms) / (presc + 1)) as u32,
+            None => 0
+        };
+
+        without_interrupts(|| {
+            self.unlock();
+            self.reg.tovalh.write(toval.get_bits(16..32) as u16);
+            self.reg.tovall.write(toval.get_bits(0..16) as u16);
+            self.reg.winh.write(win.get_bits(16..32) as u16);
+            self.reg.winl.write(win.get_bits(0..16) as u16);
+            self.reg.presc.write((presc as u16) << 8);
+            self.reg.stctrlh.update(|ctrl| {
+                ctrl.set_bit(7, config.run_in_wait);
+                ctrl.set_bit(6, config.run_in_stop);
+                ctrl.set_bit(5, config.run_in_debug);
+                // Keep the watchdog unlockable so it can be changed
+                // (or disabled) again later.
+                ctrl.set_bit(4, true);
+                ctrl.set_bit(3, config.window_ms.is_some());
+                ctrl.set_bit(1, config.clock != WatchdogClock::Lpo);
+                ctrl.set_bit(0, true);
+            });
+        });
+    }
+
+    // Have the watchdog raise its interrupt before resetting the chip,
+    // so the stuck code can be recorded. See `CrashRecord::take`.
+    pub fn enable_crash_capture(&mut self, nvic: &mut Nvic) {
+        without_interrupts(|| {
+            self.unlock();
+            self.reg.stctrlh.update(|ctrl| {
+                ctrl.set_bit(2, true);
+            });
+        });
+        nvic.enable(22);
+    }
+
+    // How many times the watchdog has reset the chip since power-on
+    // (or since `clear_reset_count`).
+    pub fn reset_count(&self) -> u16 {
+        self.reg.rstcnt.read()
+    }
+
+    pub fn clear_reset_count(&mut self) {
+        // Each bit is cleared by writing a 1 to it.
+        self.reg.rstcnt.write(0xFFFF);
+    }
+
+    // The watchdog counter, in (prescaled) watchdog clock ticks since
+    // the last refresh.
+    pub fn timer_output(&self) -> u32 {
+        // The counter can carry between reading the two halves, so
+        // read the high half again to make sure it didn't.
+        loop {
+            let high = self.reg.tmrouth.read();
+            let low = self.reg.tmroutl.read();
+            if self.reg.tmrouth.read() == high {
+                return u32::from(high) << 16 | u32::from(low);
+            }
+        }
+    }
+
+    // The counter value at which the watchdog fires.
+    pub fn timeout_value(&self) -> u32 {
+        u32::from(self.reg.tovalh.read()) << 16 | u32::from(self.reg.tovall.read())
+    }
+
+    // How many ticks are left before the watchdog fires. Checking this
+    // just before each `feed` shows how close we came.
+    pub fn remaining(&self) -> u32 {
+        self.timeout_value().saturating_sub(self.timer_output())
+    }
+
+    // Runs from RAM, so that a flash wait state can't push the second
+    // half of the refresh past its deadline.
+    #[link_section = ".ramfunc"]
+    #[inline(never)]
+    pub fn feed(&mut self) {
+        // Both halves of the refresh have to land within 20 bus
+        // clocks of each other.
+        without_interrupts(|| {
+            self.reg.refresh.write(0xA602);
+            self.reg.refresh.write(0xB480);
+        });
+    }
+
+    // Open the configuration registers for writing. They close again
+    // after 256 bus clocks, so this has to be followed immediately by
+    // the writes, with interrupts off.
+    fn unlock(&mut self) {
+        self.reg.unlock.write(0xC520);
+        self.reg.unlock.write(0xD928);
+        unsafe {
+            asm!("nop");
+            asm!("nop");
+        }
+    }
+}
+
+impl CrashRecord {
+    // Returns the record left by a watchdog reset, if the last reset
+    // was one. The record is cleared, so it's only returned once.
+    pub fn take() -> Option<CrashRecord> {
+        unsafe {
+            let record = ptr::read_volatile(ptr::addr_of!(CRASH_RECORD));
+            ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD.magic), 0);
+            ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD.task_len), 0);
+            if record.magic == CRASH_MAGIC {
+                Some(record)
+            } else {
+                None
+            }
+        }
+    }
+
+    // The supervised task that missed its deadline, if any.
+    pub fn task(&self) -> Option<&str> {
+        let len = self.task_len as usize;
+        if len == 0 || len > TASK_NAME_LEN {
+            return None;
+        }
+        str::from_utf8(&self.task_name[..len]).ok()
+    }
+}
+
+impl fmt::Display for CrashRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Watchdog reset at pc {:#010x}, lr {:#010x}, sp {:#010x}", self.pc, self.lr, self.sp)?;
+        if let Some(task) = self.task() {
+            writeln!(f, "Task '{}' missed its deadline", task)?;
         }
+        writeln!(f, "r0 {:#010x} r1 {:#010x} r2 {:#010x} r3 {:#010x} r12 {:#010x} xpsr {:#010x}",
+                 self.r0, self.r1, self.r2, self.r3, self.r12, self.xpsr)?;
+        write!(f, "stack:")?;
+        for word in &self.stack {
+            write!(f, " {:#010x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Supervisor {
+        Supervisor::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor {
+            tasks: [None; MAX_TASKS],
+            checkins: Default::default()
+        }
+    }
+
+    pub fn register(&mut self, name: &'static str, deadline_ms: u32) -> TaskId {
+        let slot = self.tasks.iter().position(|t| t.is_none())
+            .unwrap_or_else(|| panic!("Cannot supervise more than {} tasks", MAX_TASKS));
+        self.tasks[slot] = Some(SupervisedTask { name, deadline_ms, last_seen: None });
+        self.checkins[slot].store(false, Ordering::Relaxed);
+        TaskId(slot)
+    }
+
+    pub fn unregister(&mut self, task: TaskId) {
+        self.tasks[task.0] = None;
+    }
+
+    // Called by each task to say it's still making progress.
+    pub fn checkin(&self, task: TaskId) {
+        self.checkins[task.0].store(true, Ordering::Relaxed);
+    }
+
+    // Call this regularly with the current time. The watchdog is fed
+    // only if no task is overdue; otherwise the first overdue task is
+    // returned (and recorded for the crash report).
+    pub fn poll(&mut self, now_ms: u32, watchdog: &mut Watchdog) -> Option<&'static str> {
+        let mut missing = None;
+        for (task, checkin) in self.tasks.iter_mut().zip(self.checkins.iter()) {
+            if let Some(ref mut task) = *task {
+                if checkin.swap(false, Ordering::Relaxed) || task.last_seen.is_none() {
+                    task.last_seen = Some(now_ms);
+                }
+                let last_seen = task.last_seen.unwrap_or(now_ms);
+                if missing.is_none() && now_ms.wrapping_sub(last_seen) > task.deadline_ms {
+                    missing = Some(task.name);
+                }
+            }
+        }
+
+        unsafe {
+            match missing {
+                Some(name) => {
+                    let (bytes, len) = task_name(name);
+                    ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD.task_name), bytes);
+                    ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD.task_len), len as u32);
+                },
+                None => {
+                    ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD.task_len), 0);
+                    watchdog.feed();
+                }
+            }
+        }
+        missing
+    }
+}
+
+// Copy as much of `name` as fits in a crash record, without cutting
+// a character in half.
+fn task_name(name: &str) -> ([u8; TASK_NAME_LEN], usize) {
+    let mut len = cmp::min(name.len(), TASK_NAME_LEN);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut bytes = [0; TASK_NAME_LEN];
+    bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+    (bytes, len)
+}
+
+// The watchdog resets the chip 256 bus clocks after raising its
+// interrupt, so there's no time for anything but copying the frame.
+// This has to be naked so we can find the frame before a prologue
+// moves the stack pointer. It's entered with the EXC_RETURN value
+// still in lr, so `watchdog_capture` returning ends the exception.
+#[cfg(not(test))]
+#[unsafe(naked)]
+pub unsafe extern "C" fn watchdog_isr() {
+    naked_asm!("tst lr, #4
+                ite eq
+                mrseq r0, msp
+                mrsne r0, psp
+                b {capture}",
+               capture = sym watchdog_capture);
+}
+
+#[cfg(not(test))]
+unsafe extern "C" fn watchdog_capture(frame: *const u32) {
+    // IRQ 22 is shared with the EWM. If the watchdog hasn't flagged a
+    // timeout, the interrupt belongs to whoever registered for it.
+    let wdog = &*(0x40052000 as *const WatchdogRegs);
+    if !wdog.stctrll.read().get_bit(15) {
+        Interrupt::Watchdog.run_handler();
+        return;
+    }
+
+    let record = &mut *ptr::addr_of_mut!(CRASH_RECORD);
+    record.r0 = *frame.offset(0);
+    record.r1 = *frame.offset(1);
+    record.r2 = *frame.offset(2);
+    record.r3 = *frame.offset(3);
+    record.r12 = *frame.offset(4);
+    record.lr = *frame.offset(5);
+    record.pc = *frame.offset(6);
+    record.xpsr = *frame.offset(7);
+    // The stuck code's stack starts right past the 8-word frame.
+    let stack = frame.offset(8);
+    record.sp = stack as u32;
+    let stack_top = _stack_top as unsafe extern "C" fn() as usize as *const u32;
+    for (i, word) in record.stack.iter_mut().enumerate() {
+        let addr = stack.add(i);
+        *word = if addr < stack_top { *addr } else { 0 };
+    }
+    ptr::write_volatile(&mut record.magic, CRASH_MAGIC);
+    loop {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_name_fits() {
+        let (bytes, len) = task_name("sensors");
+        assert_eq!(&bytes[..len], b"sensors");
+    }
+
+    #[test]
+    fn task_name_truncates_on_char_boundary() {
+        let (bytes, len) = task_name("a long task name");
+        assert_eq!(&bytes[..len], b"a long task name");
+        // The 16th byte is in the middle of the 'é'.
+        let (bytes, len) = task_name("a long task namé");
+        assert_eq!(&bytes[..len], b"a long task nam");
     }
 }